@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::hiqueries::HiQuery;
+use crate::hivecs::HiVec;
+use crate::lattices::Lattice;
+
+/*
+Caches the results of equality queries on a HiVec. The cache remembers the
+content_fingerprint it was filled against and starts over when the HiVec's
+fingerprint differs, so it never needs clearing by hand. It inherits the
+fingerprint's blind spot: edits that leave every top-layer block range
+unchanged are not noticed.
+*/
+#[derive(Debug, Clone)]
+pub struct QueryCache<T> {
+    fingerprint: Option<u64>,
+    equals: HashMap<T, Vec<usize>>,
+    misses: usize,
+}
+
+impl<T: Copy + Lattice + Eq + Hash> Default for QueryCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Copy + Lattice + Eq + Hash> QueryCache<T> {
+    pub fn new() -> Self {
+        QueryCache {
+            fingerprint: None,
+            equals: HashMap::new(),
+            misses: 0,
+        }
+    }
+
+    pub fn query_equals<const N: usize, const FANOUT: usize>(
+        &mut self,
+        hiv: &HiVec<T, N, FANOUT>,
+        item: T,
+    ) -> &[usize] {
+        let fingerprint = hiv.content_fingerprint();
+        if self.fingerprint != Some(fingerprint) {
+            self.equals.clear();
+            self.fingerprint = Some(fingerprint);
+        }
+        self.equals.entry(item).or_insert_with(|| {
+            self.misses += 1;
+            hiv.query_equals(item).iter().collect()
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.equals.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.equals.is_empty()
+    }
+
+    /*
+    Number of lookups that had to run the query.
+    */
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+}
@@ -0,0 +1,225 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Mutex;
+
+use crate::hiqueries::HiQuery;
+use crate::hivecs::HiVec;
+use crate::lattices::{FreeL128, FreeL32, FreeL64, Lattice, LatticeRange};
+
+/*
+Types with a fixed-size little-endian encoding, so that ranges in a layer file
+can be addressed by index.
+*/
+pub trait FixedWidth: Sized {
+    const WIDTH: usize;
+    fn encode(&self, out: &mut Vec<u8>);
+    fn decode(bytes: &[u8]) -> Self;
+}
+
+macro_rules! impl_fixed_width {
+    ($($t:ty),*) => {
+        $(
+            impl FixedWidth for $t {
+                const WIDTH: usize = std::mem::size_of::<$t>();
+                fn encode(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_le_bytes());
+                }
+                fn decode(bytes: &[u8]) -> Self {
+                    <$t>::from_le_bytes(bytes.try_into().expect("Wrong width"))
+                }
+            }
+        )*
+    };
+}
+
+impl_fixed_width!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl FixedWidth for bool {
+    const WIDTH: usize = 1;
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+    fn decode(bytes: &[u8]) -> Self {
+        bytes[0] != 0
+    }
+}
+
+impl FixedWidth for FreeL32 {
+    const WIDTH: usize = 4;
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.val.encode(out)
+    }
+    fn decode(bytes: &[u8]) -> Self {
+        FreeL32::new(u32::decode(bytes))
+    }
+}
+
+impl FixedWidth for FreeL64 {
+    const WIDTH: usize = 8;
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.val.encode(out)
+    }
+    fn decode(bytes: &[u8]) -> Self {
+        FreeL64::new(u64::decode(bytes))
+    }
+}
+
+impl FixedWidth for FreeL128 {
+    const WIDTH: usize = 16;
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.val.encode(out)
+    }
+    fn decode(bytes: &[u8]) -> Self {
+        FreeL128::new(u128::decode(bytes))
+    }
+}
+
+/*
+A HiVec whose lowest layers live in a file and are read on demand. Layers
+0..disk_layers are written out on construction, the rest stay in memory along
+with the table. The file is not memory mapped: each block range is read with a
+seek and a read, under a lock since both move the file's cursor.
+*/
+pub struct DiskBackedHiVec<T, const N: usize, const FANOUT: usize> {
+    table: Vec<T>,
+    disk_layers: usize,
+    offsets: Vec<u64>,
+    file: Mutex<File>,
+    resident: Vec<Vec<LatticeRange<T>>>,
+}
+
+impl<T: Copy + Lattice + FixedWidth, const N: usize, const FANOUT: usize>
+    DiskBackedHiVec<T, N, FANOUT>
+{
+    /*
+    Moves the lowest disk_layers layers of hiv into file, which should be
+    empty and opened for reading and writing.
+    */
+    pub fn new(hiv: HiVec<T, N, FANOUT>, disk_layers: usize, mut file: File) -> io::Result<Self> {
+        assert!(disk_layers <= N, "More disk layers than layers");
+        let (table, mut layers) = hiv.into_parts();
+        let resident = layers.split_off(disk_layers);
+        let mut offsets = Vec::with_capacity(disk_layers);
+        let mut offset = 0;
+        let mut buf = Vec::new();
+        for layer in &layers {
+            offsets.push(offset);
+            buf.clear();
+            for r in layer {
+                r.top().encode(&mut buf);
+                r.bottom().encode(&mut buf);
+            }
+            file.write_all(&buf)?;
+            offset += buf.len() as u64;
+        }
+        file.flush()?;
+        Ok(DiskBackedHiVec {
+            table,
+            disk_layers,
+            offsets,
+            file: Mutex::new(file),
+            resident,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+
+    pub fn get(&self, i: usize) -> Option<&T> {
+        self.table.get(i)
+    }
+
+    pub fn disk_layers(&self) -> usize {
+        self.disk_layers
+    }
+
+    /*
+    The range of a block in a HiVec layer, reading it from the file if needed.
+    */
+    pub fn layer_range(&self, layer: usize, block: usize) -> io::Result<LatticeRange<T>> {
+        if layer >= self.disk_layers {
+            return Ok(self.resident[layer - self.disk_layers][block]);
+        }
+        let mut buf = vec![0; 2 * T::WIDTH];
+        let mut file = self.file.lock().expect("Layer file lock poisoned");
+        file.seek(SeekFrom::Start(
+            self.offsets[layer] + (block * buf.len()) as u64,
+        ))?;
+        file.read_exact(&mut buf)?;
+        let (top, bottom) = buf.split_at(T::WIDTH);
+        Ok(LatticeRange::new(T::decode(top), T::decode(bottom)))
+    }
+
+    pub fn query_equals(&self, item: T) -> DiskEqualsQuery<'_, T, N, FANOUT> {
+        DiskEqualsQuery { item, hiv: self }
+    }
+
+    pub fn query_range(&self, range: LatticeRange<T>) -> DiskRangeQuery<'_, T, N, FANOUT> {
+        DiskRangeQuery { range, hiv: self }
+    }
+}
+
+/*
+The disk queries treat a failed read as a block that may match, so an I/O
+error only costs a slower scan.
+*/
+pub struct DiskEqualsQuery<'a, T, const N: usize, const FANOUT: usize> {
+    item: T,
+    hiv: &'a DiskBackedHiVec<T, N, FANOUT>,
+}
+
+impl<'a, T: Copy + Lattice + FixedWidth, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT>
+    for DiskEqualsQuery<'a, T, N, FANOUT>
+{
+    fn length(&self) -> usize {
+        self.hiv.len()
+    }
+    fn query_at(&self, i: usize) -> bool {
+        self.hiv
+            .get(i)
+            .map(|&x| x == self.item)
+            .expect("Out of bounds")
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            self.hiv
+                .layer_range(layer - 1, i)
+                .map_or(true, |r| r.contains(&self.item))
+        }
+    }
+}
+
+pub struct DiskRangeQuery<'a, T, const N: usize, const FANOUT: usize> {
+    range: LatticeRange<T>,
+    hiv: &'a DiskBackedHiVec<T, N, FANOUT>,
+}
+
+impl<'a, T: Copy + Lattice + FixedWidth, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT>
+    for DiskRangeQuery<'a, T, N, FANOUT>
+{
+    fn length(&self) -> usize {
+        self.hiv.len()
+    }
+    fn query_at(&self, i: usize) -> bool {
+        self.hiv
+            .get(i)
+            .map(|x| self.range.contains(x))
+            .expect("Out of bounds")
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            self.hiv
+                .layer_range(layer - 1, i)
+                .map_or(true, |r| !r.intersect(self.range).isempty())
+        }
+    }
+}
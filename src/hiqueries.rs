@@ -1,5 +1,9 @@
+use std::cell::{Cell, OnceCell};
+use std::fmt::{Debug, Formatter};
 use std::sync::Arc;
 
+use crate::hivecs::HiVec;
+
 /*
 Trait for lazy heirarchical query objects. Building a tree of them lets you
 build boolean queries, and consider which power-of-FANOUT blocks in a vec contain a
@@ -16,35 +20,374 @@ pub trait HiQuery<const N: usize, const FANOUT: usize> {
     */
     fn hiquery(&self, layer: usize, i: usize) -> bool; // Layers in range 0 ..= N
 
+    /*
+    The dual of hiquery: may only return true if query_at is true for every element
+    of the chunk. The default never claims this, which is always correct.
+    */
+    fn hiquery_all(&self, layer: usize, i: usize) -> bool {
+        layer == 0 && self.query_at(i)
+    }
+
+    /*
+    Whether the chunk may hold an element for which query_at is false, the test
+    NotQuery prunes with. It must be true whenever some element doesn't match, and
+    defaults to !hiquery_all, so queries only get a pruning negation by overriding
+    one of the two.
+    */
+    fn block_has_nonmatch(&self, layer: usize, i: usize) -> bool {
+        !self.hiquery_all(layer, i)
+    }
+
+    /*
+    Spans shorter than this are scanned linearly, since climbing the hierarchy costs
+    more than it saves on them. Override to tune it for a query type.
+    */
+    fn linear_scan_threshold(&self) -> usize {
+        2 * FANOUT
+    }
+
+    /*
+    Describes why query_at(i) is true or false. Combinators report which of their
+    subqueries failed, leaves just report their own result.
+    */
+    fn explain(&self, i: usize) -> String {
+        format!("{} = {}", query_name::<Self>(), self.query_at(i))
+    }
+
     /*
     Finds the next index after i (including i itself) that for which queryat(i) is true.
+    The top layer is just a sequence of blocks, so vectors longer than FANOUT^N are
+    covered too: past each top block the climb starts over from the leaves.
+
+    The climb only goes up a layer while j is a multiple of FANOUT, so the block
+    probed at layer l + 1 always starts at i. From an unaligned i it steps to the next
+    boundary of each layer in turn, at most FANOUT - 1 steps per layer. Probing the
+    block around an unaligned i instead would mostly be wasted, since iterators call
+    this just past a match, which lies in that same block.
+    */
+    fn findnext(&self, i: usize) -> Option<usize> {
+        self.findnext_before(i, self.length())
+    }
+
+    /*
+    Like findnext, but only looks for matches in i..end. The climb never starts on a
+    block at or past end, so a miss costs as much as the span searched rather than
+    the distance to the next match.
+    */
+    fn findnext_before(&self, i: usize, end: usize) -> Option<usize> {
+        findnext_until(self, i, end, || false)
+    }
+
+    /*
+    Finds the previous index before i (including i itself) for which query_at is true.
+    Mirrors findnext, climbing past empty blocks that end at i.
+    */
+    fn findprev(&self, i: usize) -> Option<usize> {
+        self.findprev_after(i, 0)
+    }
+
+    /*
+    Like findprev, but only looks for matches in start..=i, the mirror image of
+    findnext_before.
     */
-    fn findnext(&self, mut i: usize) -> Option<usize> {
-        while i < self.length() {
+    fn findprev_after(&self, i: usize, start: usize) -> Option<usize> {
+        let mut i = i.min(self.length().checked_sub(1)?);
+        if i < start {
+            return None;
+        }
+        if i - start < self.linear_scan_threshold() {
+            return (start..=i).rev().find(|&j| self.query_at(j));
+        }
+        loop {
             if self.query_at(i) {
                 return Some(i);
             }
             let mut step = 1;
             let mut l = 0;
             let mut j = i;
-            while l < N && j % FANOUT == 0 && self.hiquery(l, j) {
+            while l < N && (j + 1).is_multiple_of(FANOUT) && !self.hiquery(l + 1, j / FANOUT) {
                 l += 1;
                 j /= FANOUT;
                 step *= FANOUT;
             }
-            i += step;
+            if i < start + step {
+                return None;
+            }
+            i -= step;
         }
-        None
     }
 
+    fn findlast(&self) -> Option<usize> {
+        self.findprev(self.length().checked_sub(1)?)
+    }
+
+    fn first(&self) -> Option<usize> {
+        self.findnext(0)
+    }
+
+    fn last(&self) -> Option<usize> {
+        self.findlast()
+    }
+
+    fn any(&self) -> bool {
+        self.findnext(0).is_some()
+    }
+
+    /*
+    Whether every index matches, stopping at the first block known to hold a
+    non-match. True for an empty query.
+    */
+    fn all(&self) -> bool {
+        (0..blocks_at::<FANOUT>(self.length(), N)).all(|b| all_topdown(self, N, b))
+    }
+
+    /*
+    The first k and the last k matches, both in ascending order. When there are fewer
+    than 2k matches the tail only holds those not already in the head.
+    */
+    fn head_tail(&self, k: usize) -> (Vec<usize>, Vec<usize>) {
+        let mut head = Vec::with_capacity(k);
+        let mut i = 0;
+        while head.len() < k {
+            match self.findnext(i) {
+                Some(j) => {
+                    head.push(j);
+                    i = j + 1;
+                }
+                None => return (head, Vec::new()),
+            }
+        }
+        let mut tail = Vec::with_capacity(k);
+        let mut next = self.findlast();
+        while let Some(j) = next {
+            if tail.len() == k || head.last().is_some_and(|&h| j <= h) {
+                break;
+            }
+            tail.push(j);
+            next = j.checked_sub(1).and_then(|j| self.findprev(j));
+        }
+        tail.reverse();
+        (head, tail)
+    }
+
+    /*
+    The start and length of the first longest run of consecutive matches. Chunks
+    where hiquery_all holds extend a run in one step, so only the elements at the
+    boundaries of runs are probed individually.
+    */
+    fn longest_run(&self) -> Option<(usize, usize)> {
+        let len = self.length();
+        let mut best: Option<(usize, usize)> = None;
+        let mut i = 0;
+        while let Some(start) = self.findnext(i) {
+            let mut end = start;
+            while end < len {
+                let mut step = 1;
+                let mut l = 0;
+                let mut j = end;
+                while l < N && j.is_multiple_of(FANOUT) && self.hiquery_all(l + 1, j / FANOUT) {
+                    l += 1;
+                    j /= FANOUT;
+                    step *= FANOUT;
+                }
+                if l == 0 && !self.query_at(end) {
+                    break;
+                }
+                end = (end + step).min(len);
+            }
+            if best.is_none_or(|(_, n)| end - start > n) {
+                best = Some((start, end - start));
+            }
+            i = end;
+        }
+        best
+    }
+
+    /*
+    Up to k matches closest to pivot, nearest first. On ties the lower index comes
+    first. Each side is advanced with findprev or findnext.
+    */
+    fn nearest_matches(&self, pivot: usize, k: usize) -> Vec<usize> {
+        let mut out = Vec::with_capacity(k);
+        let mut left = pivot.checked_sub(1).and_then(|i| self.findprev(i));
+        let mut right = self.findnext(pivot);
+        while out.len() < k {
+            let take_left = match (left, right) {
+                (Some(l), Some(r)) => pivot - l <= r - pivot,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            if take_left {
+                let l = left.expect("Impossible: no left match");
+                out.push(l);
+                left = l.checked_sub(1).and_then(|i| self.findprev(i));
+            } else {
+                let r = right.expect("Impossible: no right match");
+                out.push(r);
+                right = self.findnext(r + 1);
+            }
+        }
+        out
+    }
+
+    /*
+    Counts matches top-down, so blocks known to be empty or full cost one probe
+    each instead of a walk over their elements.
+    */
     fn count(&self) -> usize {
-        let mut n = 0;
+        let len = self.length();
+        if len < self.linear_scan_threshold() {
+            return (0..len).filter(|&j| self.query_at(j)).count();
+        }
+        (0..blocks_at::<FANOUT>(len, N))
+            .map(|b| count_topdown(self, N, b))
+            .sum()
+    }
+
+    /*
+    The number of matches in block i of the given layer, computed like count.
+    */
+    fn block_count(&self, layer: usize, i: usize) -> usize {
+        count_topdown(self, layer, i)
+    }
+
+    /*
+    The index of the k-th match (from 0), found by skipping whole blocks with
+    fewer than the remaining number of matches and descending into the one that
+    holds it.
+    */
+    fn nth(&self, mut k: usize) -> Option<usize> {
+        let (mut layer, mut start, mut end) = (N, 0, blocks_at::<FANOUT>(self.length(), N));
+        loop {
+            let mut b = start;
+            loop {
+                if b == end {
+                    return None;
+                }
+                let c = self.block_count(layer, b);
+                if k < c {
+                    break;
+                }
+                k -= c;
+                b += 1;
+            }
+            if layer == 0 {
+                return Some(b);
+            }
+            layer -= 1;
+            start = b * FANOUT;
+            end = blocks_at::<FANOUT>(self.length(), layer).min(start + FANOUT);
+        }
+    }
+
+    /*
+    The matches in ascending order, in a Vec sized with count up front.
+    */
+    fn collect_indices(&self) -> Vec<usize> {
+        let mut out = Vec::with_capacity(self.count());
+        let mut next = self.findnext(0);
+        while let Some(i) = next {
+            out.push(i);
+            next = self.findnext(i + 1);
+        }
+        out
+    }
+
+    fn collect_with(&self, strategy: TraversalStrategy) -> Vec<usize> {
+        let mut out = Vec::new();
+        match strategy {
+            TraversalStrategy::BottomUpSkip => {
+                let mut i = 0;
+                while let Some(j) = self.findnext(i) {
+                    out.push(j);
+                    i = j + 1;
+                }
+            }
+            TraversalStrategy::TopDownRecurse => {
+                for b in 0..blocks_at::<FANOUT>(self.length(), N) {
+                    collect_topdown(self, N, b, &mut out);
+                }
+            }
+        }
+        out
+    }
+
+    /*
+    Collects matches until max_probes calls to query_at and hiquery have been made.
+    Returns the matches found, which are a prefix of the full result, and whether
+    the traversal finished within the budget.
+    */
+    fn collect_budgeted(&self, max_probes: usize) -> (Vec<usize>, bool) {
+        let budgeted = Budgeted {
+            q: self,
+            remaining: Cell::new(max_probes),
+            exhausted: Cell::new(false),
+        };
+        let mut out = Vec::new();
+        let mut i = 0;
+        while let Some(j) = budgeted.findnext(i) {
+            out.push(j);
+            i = j + 1;
+        }
+        (out, !budgeted.exhausted.get())
+    }
+
+    fn mask(&self) -> Vec<bool> {
+        let mut mask = vec![false; self.length()];
         let mut i = 0;
         while let Some(j) = self.findnext(i) {
+            mask[j] = true;
             i = j + 1;
-            n += 1;
         }
-        return n;
+        mask
+    }
+
+    /*
+    Evaluates the query once into a boolean column with its own hierarchy, so that
+    reusing it is just a query_equals(true) on the result.
+    */
+    fn materialize(&self) -> HiVec<bool, N, FANOUT> {
+        HiVec::new(self.mask())
+    }
+
+    /*
+    Run-length encodes the selection as (selected, run_length) pairs, for pushing down
+    to readers that work in row groups of group_size rows. A run either lies within a
+    single group or covers whole groups, so unselected groups show up as skip runs.
+    The non-matching stretches are found with findnext, so they cost no per-row probes.
+    */
+    fn to_rle_selection(&self, group_size: usize) -> Vec<(bool, usize)> {
+        assert!(group_size > 0, "Row groups must be non-empty");
+        let mut runs = Vec::new();
+        let mut emit = |selected: bool, mut from: usize, to: usize| {
+            let head_end = from.next_multiple_of(group_size).min(to);
+            if from < head_end && !from.is_multiple_of(group_size) {
+                runs.push((selected, head_end - from));
+                from = head_end;
+            }
+            let whole = (to - from) / group_size * group_size;
+            if whole > 0 {
+                runs.push((selected, whole));
+                from += whole;
+            }
+            if from < to {
+                runs.push((selected, to - from));
+            }
+        };
+        let len = self.length();
+        let mut i = 0;
+        while i < len {
+            let start = self.findnext(i).unwrap_or(len);
+            emit(false, i, start);
+            let mut end = start;
+            while end < len && self.query_at(end) {
+                end += 1;
+            }
+            emit(true, start, end);
+            i = end;
+        }
+        runs
     }
 
     fn and<Q2: HiQuery<N, FANOUT> + Sized>(
@@ -75,11 +418,92 @@ pub trait HiQuery<const N: usize, const FANOUT: usize> {
         }
     }
 
-    fn iter(&self) -> HiQIter<Self, N, FANOUT>
+    fn xor<Q2: HiQuery<N, FANOUT> + Sized>(
+        self: Arc<Self>,
+        other: Arc<Q2>,
+    ) -> XorQuery<Self, Q2, N, FANOUT>
+    where
+        Self: Sized,
+    {
+        assert_eq!(self.length(), other.length());
+        XorQuery {
+            q1: self,
+            q2: other,
+        }
+    }
+
+    fn iter(&self) -> HiQIter<'_, Self, N, FANOUT>
+    where
+        Self: Sized,
+    {
+        HiQIter {
+            hq: self,
+            i: 0,
+            end: self.length(),
+        }
+    }
+
+    /*
+    The matches within range, without looking past its end.
+    */
+    fn iter_range(&self, range: std::ops::Range<usize>) -> HiQIter<'_, Self, N, FANOUT>
+    where
+        Self: Sized,
+    {
+        HiQIter {
+            hq: self,
+            i: range.start,
+            end: range.end.min(self.length()),
+        }
+    }
+
+    /*
+    The matches on the rayon thread pool, in ascending order when collected. The
+    index space is cut into shards of whole blocks, as large as possible while still
+    giving each thread a few, and each shard is walked with iter_range, which stops
+    at the shard's end.
+    */
+    #[cfg(feature = "rayon")]
+    fn par_iter(&self) -> impl rayon::iter::ParallelIterator<Item = usize> + '_
+    where
+        Self: Sized + Sync,
+    {
+        use rayon::prelude::*;
+        let len = self.length();
+        let mut span = FANOUT.saturating_pow(N as u32).max(1);
+        while span > FANOUT && len / span < 4 * rayon::current_num_threads() {
+            span /= FANOUT;
+        }
+        (0..len.div_ceil(span))
+            .into_par_iter()
+            .flat_map_iter(move |s| self.iter_range(s * span..(s + 1) * span))
+    }
+
+    /*
+    Iterates over the matches in descending order, without collecting them.
+    */
+    fn iter_desc(&self) -> HiQRevIter<'_, Self, N, FANOUT>
     where
         Self: Sized,
     {
-        HiQIter { hq: self, i: 0 }
+        HiQRevIter {
+            hq: self,
+            upto: self.length().checked_sub(1),
+        }
+    }
+
+    /*
+    Bundles the length, count, indices and mask of the query. The matches are only
+    collected when first asked for, and then shared by all of them.
+    */
+    fn evaluate(&self) -> QueryResult<'_, Self, N, FANOUT>
+    where
+        Self: Sized,
+    {
+        QueryResult {
+            q: self,
+            indices: OnceCell::new(),
+        }
     }
 
     fn rc(self) -> Arc<Self>
@@ -88,11 +512,323 @@ pub trait HiQuery<const N: usize, const FANOUT: usize> {
     {
         Arc::new(self)
     }
+
+    fn boxed<'a>(self) -> BoxedQuery<'a, N, FANOUT>
+    where
+        Self: Sized + 'a,
+    {
+        BoxedQuery::new(self)
+    }
+}
+
+/*
+How collect_with walks the hierarchy. BottomUpSkip is findnext: scan forward and
+climb as far as possible past empty blocks. TopDownRecurse starts from the top
+layer and only descends into blocks whose hiquery is true, which tends to win
+when matches are clustered.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraversalStrategy {
+    BottomUpSkip,
+    TopDownRecurse,
+}
+
+/*
+The body of findnext_before. Both loops give up as soon as stop returns true, which
+lets Budgeted end a traversal once its budget is spent.
+*/
+fn findnext_until<Q, const N: usize, const FANOUT: usize>(
+    q: &Q,
+    mut i: usize,
+    end: usize,
+    stop: impl Fn() -> bool,
+) -> Option<usize>
+where
+    Q: HiQuery<N, FANOUT> + ?Sized,
+{
+    let len = end.min(q.length());
+    if len.saturating_sub(i) < q.linear_scan_threshold() {
+        return (i..len).take_while(|_| !stop()).find(|&j| q.query_at(j));
+    }
+    while i < len && !stop() {
+        if q.query_at(i) {
+            return Some(i);
+        }
+        let mut step = 1;
+        let mut l = 0;
+        let mut j = i;
+        while l < N && j.is_multiple_of(FANOUT) && !q.hiquery(l + 1, j / FANOUT) {
+            l += 1;
+            j /= FANOUT;
+            step *= FANOUT;
+        }
+        // The last block may be partial or cross end, so don't step past it.
+        i = (i + step).min(len);
+    }
+    None
+}
+
+// Number of blocks of FANOUT^layer elements needed to cover len elements.
+fn blocks_at<const FANOUT: usize>(len: usize, layer: usize) -> usize {
+    len.div_ceil(FANOUT.saturating_pow(layer as u32))
+}
+
+fn collect_topdown<Q, const N: usize, const FANOUT: usize>(
+    q: &Q,
+    layer: usize,
+    block: usize,
+    out: &mut Vec<usize>,
+) where
+    Q: HiQuery<N, FANOUT> + ?Sized,
+{
+    if layer == 0 {
+        if q.query_at(block) {
+            out.push(block);
+        }
+    } else if q.hiquery(layer, block) {
+        let end = blocks_at::<FANOUT>(q.length(), layer - 1).min((block + 1) * FANOUT);
+        for child in block * FANOUT..end {
+            collect_topdown(q, layer - 1, child, out);
+        }
+    }
+}
+
+fn all_topdown<Q, const N: usize, const FANOUT: usize>(q: &Q, layer: usize, block: usize) -> bool
+where
+    Q: HiQuery<N, FANOUT> + ?Sized,
+{
+    match block_state(q, layer, block) {
+        Some(all) => all,
+        None => {
+            let end = blocks_at::<FANOUT>(q.length(), layer - 1).min((block + 1) * FANOUT);
+            (block * FANOUT..end).all(|child| all_topdown(q, layer - 1, child))
+        }
+    }
+}
+
+fn count_topdown<Q, const N: usize, const FANOUT: usize>(q: &Q, layer: usize, block: usize) -> usize
+where
+    Q: HiQuery<N, FANOUT> + ?Sized,
+{
+    match block_state(q, layer, block) {
+        Some(false) => 0,
+        Some(true) => {
+            let size = FANOUT.saturating_pow(layer as u32);
+            q.length().min(block.saturating_add(1).saturating_mul(size)) - block * size
+        }
+        None => {
+            let end = blocks_at::<FANOUT>(q.length(), layer - 1).min((block + 1) * FANOUT);
+            (block * FANOUT..end)
+                .map(|child| count_topdown(q, layer - 1, child))
+                .sum()
+        }
+    }
+}
+
+/*
+How often two aligned queries match together, alone, or not at all.
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CoOccurStats {
+    pub both: usize,
+    pub only_a: usize,
+    pub only_b: usize,
+    pub neither: usize,
+}
+
+/*
+Counts co-occurrences of a and b in one top-down pass. A block is counted
+whole once each query is known to match none or all of it, so the leaves are
+only visited where a query is mixed.
+*/
+pub fn co_occurrence<Q1, Q2, const N: usize, const FANOUT: usize>(a: &Q1, b: &Q2) -> CoOccurStats
+where
+    Q1: HiQuery<N, FANOUT> + ?Sized,
+    Q2: HiQuery<N, FANOUT> + ?Sized,
+{
+    assert_eq!(a.length(), b.length());
+    let mut stats = CoOccurStats::default();
+    for block in 0..blocks_at::<FANOUT>(a.length(), N) {
+        co_occurrence_at(a, b, N, block, &mut stats);
+    }
+    stats
+}
+
+fn co_occurrence_at<Q1, Q2, const N: usize, const FANOUT: usize>(
+    a: &Q1,
+    b: &Q2,
+    layer: usize,
+    block: usize,
+    stats: &mut CoOccurStats,
+) where
+    Q1: HiQuery<N, FANOUT> + ?Sized,
+    Q2: HiQuery<N, FANOUT> + ?Sized,
+{
+    if let (Some(ma), Some(mb)) = (block_state(a, layer, block), block_state(b, layer, block)) {
+        let size = FANOUT.saturating_pow(layer as u32);
+        let n = (block + 1).saturating_mul(size).min(a.length()) - block * size;
+        match (ma, mb) {
+            (true, true) => stats.both += n,
+            (true, false) => stats.only_a += n,
+            (false, true) => stats.only_b += n,
+            (false, false) => stats.neither += n,
+        }
+    } else {
+        let end = blocks_at::<FANOUT>(a.length(), layer - 1).min((block + 1) * FANOUT);
+        for child in block * FANOUT..end {
+            co_occurrence_at(a, b, layer - 1, child, stats);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Membership {
+    A,
+    B,
+    Both,
+}
+
+/*
+Merges the matches of two aligned queries in ascending order, tagging each index
+with the queries it matched. Every index matched by either appears exactly once.
+*/
+pub fn merge_streams<'a, Q1, Q2, const N: usize, const FANOUT: usize>(
+    a: &'a Q1,
+    b: &'a Q2,
+) -> impl Iterator<Item = (usize, Membership)> + 'a
+where
+    Q1: HiQuery<N, FANOUT>,
+    Q2: HiQuery<N, FANOUT>,
+{
+    assert_eq!(a.length(), b.length());
+    let mut a = a.iter().peekable();
+    let mut b = b.iter().peekable();
+    std::iter::from_fn(move || match (a.peek().copied(), b.peek().copied()) {
+        (Some(i), Some(j)) if i == j => {
+            a.next();
+            b.next();
+            Some((i, Membership::Both))
+        }
+        (Some(i), Some(j)) if i < j => a.next().map(|i| (i, Membership::A)),
+        (Some(_), None) => a.next().map(|i| (i, Membership::A)),
+        (_, Some(_)) => b.next().map(|j| (j, Membership::B)),
+        (None, None) => None,
+    })
+}
+
+// Some(true) if all of the block matches, Some(false) if none of it does.
+fn block_state<Q, const N: usize, const FANOUT: usize>(
+    q: &Q,
+    layer: usize,
+    block: usize,
+) -> Option<bool>
+where
+    Q: HiQuery<N, FANOUT> + ?Sized,
+{
+    if layer == 0 {
+        Some(q.query_at(block))
+    } else if !q.hiquery(layer, block) {
+        Some(false)
+    } else if q.hiquery_all(layer, block) {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+// The bare name of a query type, without its module path or parameters.
+fn query_name<Q: ?Sized>() -> &'static str {
+    let name = std::any::type_name::<Q>();
+    let name = &name[..name.find('<').unwrap_or(name.len())];
+    name.rsplit("::").next().unwrap_or(name)
 }
 
 pub trait NegatableQuery<const N: usize, const FANOUT: usize>: HiQuery<N, FANOUT> {
     type NegType: NegatableQuery<N, FANOUT>;
     fn negation(self: &Arc<Self>) -> Self::NegType;
+
+    fn not(self: &Arc<Self>) -> Self::NegType {
+        self.negation()
+    }
+}
+
+/*
+Negates any query, pruning with the inner query's block_has_nonmatch. That only
+skips blocks for queries that override it or hiquery_all, such as the equality and
+range queries; wrapping a query that keeps both defaults, like query_transitions,
+gives a correct negation that has to look at every element.
+*/
+pub struct NotQuery<Q> {
+    q: Arc<Q>,
+}
+
+impl<Q> NotQuery<Q> {
+    pub fn new(q: Arc<Q>) -> Self {
+        NotQuery { q }
+    }
+
+    pub fn inner(&self) -> &Q {
+        &self.q
+    }
+}
+
+impl<Q: HiQuery<N, FANOUT>, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT>
+    for NotQuery<Q>
+{
+    fn length(&self) -> usize {
+        self.q.length()
+    }
+    fn query_at(&self, i: usize) -> bool {
+        !self.q.query_at(i)
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            self.q.block_has_nonmatch(layer, i)
+        }
+    }
+    fn hiquery_all(&self, layer: usize, i: usize) -> bool {
+        !self.q.hiquery(layer, i)
+    }
+    fn explain(&self, i: usize) -> String {
+        format!("NOT = {}: {}", self.query_at(i), self.q.explain(i))
+    }
+}
+
+/*
+Negating twice gives back a query equivalent to the inner one, built from its own
+negation so that no wrapper is left around it.
+*/
+impl<Q: NegatableQuery<N, FANOUT>, const N: usize, const FANOUT: usize> NegatableQuery<N, FANOUT>
+    for NotQuery<Q>
+{
+    type NegType = <Q::NegType as NegatableQuery<N, FANOUT>>::NegType;
+    fn negation(self: &Arc<Self>) -> Self::NegType {
+        self.q.negation().rc().negation()
+    }
+}
+
+impl<Q: Debug> Debug for NotQuery<Q> {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_tuple("NotQuery").field(&self.q).finish()
+    }
+}
+
+/*
+Queries that can point at the value behind each match. Leaves return the element
+of their own column, so an OR over two columns reports the column that matched.
+*/
+pub trait CausalQuery<T, const N: usize, const FANOUT: usize>: HiQuery<N, FANOUT> {
+    fn cause(&self, i: usize) -> Option<&T>;
+
+    fn iter_with_cause<'a>(&'a self) -> impl Iterator<Item = (usize, &'a T)> + 'a
+    where
+        Self: Sized,
+        T: 'a,
+    {
+        self.iter().filter_map(|i| self.cause(i).map(|x| (i, x)))
+    }
 }
 
 #[derive(Clone)]
@@ -106,6 +842,16 @@ pub struct OrQuery<Q1, Q2, const N: usize, const FANOUT: usize> {
     q2: Arc<Q2>,
 }
 
+/*
+Matches where exactly one side does. A block can hold such an index whenever
+either side may match in it, since matches of the two sides need not overlap.
+*/
+#[derive(Clone)]
+pub struct XorQuery<Q1, Q2, const N: usize, const FANOUT: usize> {
+    q1: Arc<Q1>,
+    q2: Arc<Q2>,
+}
+
 impl<Q1, Q2, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT> for AndQuery<Q1, Q2, N, FANOUT>
 where
     Q1: HiQuery<N, FANOUT>,
@@ -117,9 +863,26 @@ where
     fn hiquery(&self, layer: usize, i: usize) -> bool {
         self.q1.hiquery(layer, i) && self.q2.hiquery(layer, i)
     }
+    fn hiquery_all(&self, layer: usize, i: usize) -> bool {
+        self.q1.hiquery_all(layer, i) && self.q2.hiquery_all(layer, i)
+    }
     fn length(&self) -> usize {
         self.q1.length()
     }
+    fn explain(&self, i: usize) -> String {
+        let mut failed = Vec::new();
+        if !self.q1.query_at(i) {
+            failed.push(format!("left({})", self.q1.explain(i)));
+        }
+        if !self.q2.query_at(i) {
+            failed.push(format!("right({})", self.q2.explain(i)));
+        }
+        if failed.is_empty() {
+            "AND = true".to_string()
+        } else {
+            format!("AND failed: {}", failed.join(", "))
+        }
+    }
 }
 
 impl<Q1, Q2, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT> for OrQuery<Q1, Q2, N, FANOUT>
@@ -133,9 +896,122 @@ where
     fn hiquery(&self, layer: usize, i: usize) -> bool {
         self.q1.hiquery(layer, i) || self.q2.hiquery(layer, i)
     }
+    fn hiquery_all(&self, layer: usize, i: usize) -> bool {
+        self.q1.hiquery_all(layer, i) || self.q2.hiquery_all(layer, i)
+    }
+    fn length(&self) -> usize {
+        self.q1.length()
+    }
+    fn explain(&self, i: usize) -> String {
+        if self.q1.query_at(i) {
+            format!("OR = true: left({})", self.q1.explain(i))
+        } else if self.q2.query_at(i) {
+            format!("OR = true: right({})", self.q2.explain(i))
+        } else {
+            format!(
+                "OR failed: left({}), right({})",
+                self.q1.explain(i),
+                self.q2.explain(i)
+            )
+        }
+    }
+}
+
+// An AND reports the value from its left branch.
+impl<T, Q1, Q2, const N: usize, const FANOUT: usize> CausalQuery<T, N, FANOUT>
+    for AndQuery<Q1, Q2, N, FANOUT>
+where
+    Q1: CausalQuery<T, N, FANOUT>,
+    Q2: HiQuery<N, FANOUT>,
+{
+    fn cause(&self, i: usize) -> Option<&T> {
+        if self.q2.query_at(i) {
+            self.q1.cause(i)
+        } else {
+            None
+        }
+    }
+}
+
+impl<T, Q1, Q2, const N: usize, const FANOUT: usize> CausalQuery<T, N, FANOUT>
+    for OrQuery<Q1, Q2, N, FANOUT>
+where
+    Q1: CausalQuery<T, N, FANOUT>,
+    Q2: CausalQuery<T, N, FANOUT>,
+{
+    fn cause(&self, i: usize) -> Option<&T> {
+        self.q1.cause(i).or_else(|| self.q2.cause(i))
+    }
+}
+
+impl<Q1, Q2, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT> for XorQuery<Q1, Q2, N, FANOUT>
+where
+    Q1: HiQuery<N, FANOUT>,
+    Q2: HiQuery<N, FANOUT>,
+{
+    fn query_at(&self, i: usize) -> bool {
+        self.q1.query_at(i) ^ self.q2.query_at(i)
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        self.q1.hiquery(layer, i) || self.q2.hiquery(layer, i)
+    }
+    fn hiquery_all(&self, layer: usize, i: usize) -> bool {
+        (self.q1.hiquery_all(layer, i) && !self.q2.hiquery(layer, i))
+            || (self.q2.hiquery_all(layer, i) && !self.q1.hiquery(layer, i))
+    }
     fn length(&self) -> usize {
         self.q1.length()
     }
+    fn explain(&self, i: usize) -> String {
+        format!(
+            "XOR = {}: left({}), right({})",
+            self.query_at(i),
+            self.q1.explain(i),
+            self.q2.explain(i)
+        )
+    }
+}
+
+impl<Q1, Q2, const N: usize, const FANOUT: usize> Debug for AndQuery<Q1, Q2, N, FANOUT>
+where
+    Q1: HiQuery<N, FANOUT> + Debug,
+    Q2: HiQuery<N, FANOUT> + Debug,
+{
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_struct("AndQuery")
+            .field("length", &self.length())
+            .field("q1", &self.q1)
+            .field("q2", &self.q2)
+            .finish()
+    }
+}
+
+impl<Q1, Q2, const N: usize, const FANOUT: usize> Debug for OrQuery<Q1, Q2, N, FANOUT>
+where
+    Q1: HiQuery<N, FANOUT> + Debug,
+    Q2: HiQuery<N, FANOUT> + Debug,
+{
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_struct("OrQuery")
+            .field("length", &self.length())
+            .field("q1", &self.q1)
+            .field("q2", &self.q2)
+            .finish()
+    }
+}
+
+impl<Q1, Q2, const N: usize, const FANOUT: usize> Debug for XorQuery<Q1, Q2, N, FANOUT>
+where
+    Q1: HiQuery<N, FANOUT> + Debug,
+    Q2: HiQuery<N, FANOUT> + Debug,
+{
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_struct("XorQuery")
+            .field("length", &self.length())
+            .field("q1", &self.q1)
+            .field("q2", &self.q2)
+            .finish()
+    }
 }
 
 impl<Q1, Q2, const N: usize, const FANOUT: usize> NegatableQuery<N, FANOUT>
@@ -170,9 +1046,345 @@ where
     }
 }
 
+/*
+Flattened n-ary versions of AndQuery and OrQuery over boxed children. The
+children are HiQuery<N, FANOUT> trait objects, so all of them share the
+combinator's N and FANOUT at the type level.
+*/
+pub struct IntersectionQuery<'a, const N: usize, const FANOUT: usize> {
+    children: Vec<Box<dyn HiQuery<N, FANOUT> + 'a>>,
+    length: usize,
+}
+
+pub struct UnionQuery<'a, const N: usize, const FANOUT: usize> {
+    children: Vec<Box<dyn HiQuery<N, FANOUT> + 'a>>,
+    length: usize,
+}
+
+impl<'a, const N: usize, const FANOUT: usize> IntersectionQuery<'a, N, FANOUT> {
+    /*
+    With no children, every index in 0..length matches.
+    */
+    pub fn new(length: usize) -> Self {
+        IntersectionQuery {
+            children: Vec::new(),
+            length,
+        }
+    }
+
+    pub fn push(&mut self, q: impl HiQuery<N, FANOUT> + 'a) {
+        assert_eq!(self.length, q.length());
+        self.children.push(Box::new(q));
+    }
+}
+
+impl<'a, const N: usize, const FANOUT: usize> UnionQuery<'a, N, FANOUT> {
+    /*
+    With no children, no index matches.
+    */
+    pub fn new(length: usize) -> Self {
+        UnionQuery {
+            children: Vec::new(),
+            length,
+        }
+    }
+
+    pub fn push(&mut self, q: impl HiQuery<N, FANOUT> + 'a) {
+        assert_eq!(self.length, q.length());
+        self.children.push(Box::new(q));
+    }
+}
+
+impl<'a, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT>
+    for IntersectionQuery<'a, N, FANOUT>
+{
+    fn length(&self) -> usize {
+        self.length
+    }
+    fn query_at(&self, i: usize) -> bool {
+        self.children.iter().all(|q| q.query_at(i))
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        self.children.iter().all(|q| q.hiquery(layer, i))
+    }
+    fn hiquery_all(&self, layer: usize, i: usize) -> bool {
+        self.children.iter().all(|q| q.hiquery_all(layer, i))
+    }
+}
+
+impl<'a, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT> for UnionQuery<'a, N, FANOUT> {
+    fn length(&self) -> usize {
+        self.length
+    }
+    fn query_at(&self, i: usize) -> bool {
+        self.children.iter().any(|q| q.query_at(i))
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        self.children.iter().any(|q| q.hiquery(layer, i))
+    }
+    fn hiquery_all(&self, layer: usize, i: usize) -> bool {
+        self.children.iter().any(|q| q.hiquery_all(layer, i))
+    }
+}
+
+/*
+A query matching a sorted set of indices obtained elsewhere, e.g. from another
+index, so that it can be combined with HiVec queries. A block matches when some
+index falls within its span.
+*/
+#[derive(Debug, Clone)]
+pub struct IndexSetQuery<const N: usize, const FANOUT: usize> {
+    indices: Vec<usize>,
+    length: usize,
+}
+
+impl<const N: usize, const FANOUT: usize> IndexSetQuery<N, FANOUT> {
+    pub fn from_sorted_indices(indices: Vec<usize>, length: usize) -> Self {
+        assert!(
+            indices.windows(2).all(|w| w[0] < w[1]),
+            "Indices must be sorted and distinct"
+        );
+        assert!(
+            indices.last().is_none_or(|&i| i < length),
+            "Index out of bounds"
+        );
+        IndexSetQuery { indices, length }
+    }
+}
+
+impl<const N: usize, const FANOUT: usize> HiQuery<N, FANOUT> for IndexSetQuery<N, FANOUT> {
+    fn length(&self) -> usize {
+        self.length
+    }
+    fn query_at(&self, i: usize) -> bool {
+        self.indices.binary_search(&i).is_ok()
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        let span = FANOUT.saturating_pow(layer as u32);
+        let start = i.saturating_mul(span);
+        let first = self.indices.partition_point(|&x| x < start);
+        self.indices
+            .get(first)
+            .is_some_and(|&x| x < start.saturating_add(span))
+    }
+}
+
+/*
+Erases the type of a query, so that trees of and, or and not can be built from
+runtime data: combining two BoxedQuery gives a concrete type that can be boxed
+again.
+*/
+pub struct BoxedQuery<'a, const N: usize, const FANOUT: usize> {
+    q: Box<dyn HiQuery<N, FANOUT> + 'a>,
+}
+
+impl<'a, const N: usize, const FANOUT: usize> BoxedQuery<'a, N, FANOUT> {
+    pub fn new(q: impl HiQuery<N, FANOUT> + 'a) -> Self {
+        BoxedQuery { q: Box::new(q) }
+    }
+}
+
+impl<'a, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT> for BoxedQuery<'a, N, FANOUT> {
+    fn length(&self) -> usize {
+        self.q.length()
+    }
+    fn query_at(&self, i: usize) -> bool {
+        self.q.query_at(i)
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        self.q.hiquery(layer, i)
+    }
+    fn hiquery_all(&self, layer: usize, i: usize) -> bool {
+        self.q.hiquery_all(layer, i)
+    }
+    fn block_has_nonmatch(&self, layer: usize, i: usize) -> bool {
+        self.q.block_has_nonmatch(layer, i)
+    }
+    fn linear_scan_threshold(&self) -> usize {
+        self.q.linear_scan_threshold()
+    }
+    fn explain(&self, i: usize) -> String {
+        self.q.explain(i)
+    }
+}
+
+/*
+A query matching every index or none, as the seed when folding and or or over a
+list of predicates: ConstQuery::new(true, len) is the identity of and,
+ConstQuery::new(false, len) that of or.
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ConstQuery<const N: usize, const FANOUT: usize> {
+    value: bool,
+    length: usize,
+}
+
+impl<const N: usize, const FANOUT: usize> ConstQuery<N, FANOUT> {
+    pub fn new(value: bool, length: usize) -> Self {
+        ConstQuery { value, length }
+    }
+}
+
+impl<const N: usize, const FANOUT: usize> HiQuery<N, FANOUT> for ConstQuery<N, FANOUT> {
+    fn length(&self) -> usize {
+        self.length
+    }
+    fn query_at(&self, _i: usize) -> bool {
+        self.value
+    }
+    fn hiquery(&self, _layer: usize, _i: usize) -> bool {
+        self.value
+    }
+    fn hiquery_all(&self, _layer: usize, _i: usize) -> bool {
+        self.value
+    }
+}
+
+impl<const N: usize, const FANOUT: usize> NegatableQuery<N, FANOUT> for ConstQuery<N, FANOUT> {
+    type NegType = Self;
+    fn negation(self: &Arc<Self>) -> Self::NegType {
+        ConstQuery::new(!self.value, self.length)
+    }
+}
+
+/*
+Evaluates a query over an old index space at the indices of a compacted one.
+new_to_old[k] is the old index of new index k, and must be strictly increasing,
+as it is after a retain. Monotonicity means a block of new indices maps into one
+contiguous span of old indices, so a block can be pruned with findnext on the
+original query.
+*/
+#[derive(Debug, Clone)]
+pub struct RemapQuery<Q> {
+    q: Q,
+    new_to_old: Vec<usize>,
+}
+
+impl<Q> RemapQuery<Q> {
+    pub fn new<const N: usize, const FANOUT: usize>(q: Q, new_to_old: Vec<usize>) -> Self
+    where
+        Q: HiQuery<N, FANOUT>,
+    {
+        assert!(
+            new_to_old.windows(2).all(|w| w[0] < w[1]),
+            "Remap must be strictly increasing"
+        );
+        assert!(
+            new_to_old.last().is_none_or(|&i| i < q.length()),
+            "Index out of bounds"
+        );
+        RemapQuery { q, new_to_old }
+    }
+
+    pub fn inner(&self) -> &Q {
+        &self.q
+    }
+}
+
+impl<Q: HiQuery<N, FANOUT>, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT>
+    for RemapQuery<Q>
+{
+    fn length(&self) -> usize {
+        self.new_to_old.len()
+    }
+    fn query_at(&self, i: usize) -> bool {
+        self.q.query_at(self.new_to_old[i])
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            return self.query_at(i);
+        }
+        let span = FANOUT.saturating_pow(layer as u32);
+        let start = self.new_to_old[i * span];
+        let end = self.new_to_old[(i + 1).saturating_mul(span).min(self.length()) - 1];
+        self.q.findnext_before(start, end + 1).is_some()
+    }
+}
+
+/*
+Counts down the probe budget of collect_budgeted. Once it runs out every probe
+answers false, which ends the traversal without reporting anything further.
+*/
+struct Budgeted<'a, Q: ?Sized> {
+    q: &'a Q,
+    remaining: Cell<usize>,
+    exhausted: Cell<bool>,
+}
+
+impl<'a, Q: ?Sized> Budgeted<'a, Q> {
+    fn spend(&self) -> bool {
+        if self.exhausted.get() {
+            return false;
+        }
+        match self.remaining.get().checked_sub(1) {
+            Some(r) => self.remaining.set(r),
+            None => self.exhausted.set(true),
+        }
+        !self.exhausted.get()
+    }
+}
+
+impl<'a, Q: HiQuery<N, FANOUT> + ?Sized, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT>
+    for Budgeted<'a, Q>
+{
+    fn length(&self) -> usize {
+        self.q.length()
+    }
+    fn query_at(&self, i: usize) -> bool {
+        self.spend() && self.q.query_at(i)
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        self.spend() && self.q.hiquery(layer, i)
+    }
+    fn hiquery_all(&self, layer: usize, i: usize) -> bool {
+        self.spend() && self.q.hiquery_all(layer, i)
+    }
+    fn linear_scan_threshold(&self) -> usize {
+        self.q.linear_scan_threshold()
+    }
+    fn findnext_before(&self, i: usize, end: usize) -> Option<usize> {
+        findnext_until(self, i, end, || self.exhausted.get())
+    }
+}
+
+/*
+The outputs of a query, see HiQuery::evaluate.
+*/
+pub struct QueryResult<'a, Q, const N: usize, const FANOUT: usize> {
+    q: &'a Q,
+    indices: OnceCell<Vec<usize>>,
+}
+
+impl<'a, Q: HiQuery<N, FANOUT>, const N: usize, const FANOUT: usize> QueryResult<'a, Q, N, FANOUT> {
+    pub fn length(&self) -> usize {
+        self.q.length()
+    }
+
+    pub fn indices(&self) -> &[usize] {
+        self.indices.get_or_init(|| self.q.iter().collect())
+    }
+
+    pub fn count(&self) -> usize {
+        self.indices().len()
+    }
+
+    pub fn mask(&self) -> Vec<bool> {
+        let mut mask = vec![false; self.length()];
+        for &i in self.indices() {
+            mask[i] = true;
+        }
+        mask
+    }
+}
+
+/*
+Yields matches from both ends: i is the next index to search forward from and
+end is one past the last index left for next_back, so the two never cross.
+*/
 pub struct HiQIter<'a, T: HiQuery<N, FANOUT>, const N: usize, const FANOUT: usize> {
     hq: &'a T,
     i: usize,
+    end: usize,
 }
 
 impl<'a, T: HiQuery<N, FANOUT>, const N: usize, const FANOUT: usize> Iterator
@@ -180,9 +1392,40 @@ impl<'a, T: HiQuery<N, FANOUT>, const N: usize, const FANOUT: usize> Iterator
 {
     type Item = usize;
     fn next(&mut self) -> Option<usize> {
-        self.hq.findnext(self.i).map(|i| {
-            self.i = i + 1;
-            i
-        })
+        if self.i >= self.end {
+            return None;
+        }
+        let found = self.hq.findnext_before(self.i, self.end);
+        self.i = found.map_or(self.end, |j| j + 1);
+        found
+    }
+}
+
+impl<'a, T: HiQuery<N, FANOUT>, const N: usize, const FANOUT: usize> DoubleEndedIterator
+    for HiQIter<'a, T, N, FANOUT>
+{
+    fn next_back(&mut self) -> Option<usize> {
+        if self.end <= self.i {
+            return None;
+        }
+        let found = self.hq.findprev_after(self.end - 1, self.i);
+        self.end = found.unwrap_or(self.i);
+        found
+    }
+}
+
+pub struct HiQRevIter<'a, T: HiQuery<N, FANOUT>, const N: usize, const FANOUT: usize> {
+    hq: &'a T,
+    upto: Option<usize>,
+}
+
+impl<'a, T: HiQuery<N, FANOUT>, const N: usize, const FANOUT: usize> Iterator
+    for HiQRevIter<'a, T, N, FANOUT>
+{
+    type Item = usize;
+    fn next(&mut self) -> Option<usize> {
+        let found = self.hq.findprev(self.upto?);
+        self.upto = found.and_then(|i| i.checked_sub(1));
+        found
     }
 }
@@ -0,0 +1,157 @@
+use std::any::Any;
+use std::fmt::{Debug, Formatter};
+
+use crate::hiqueries::HiQuery;
+use crate::hivecs::HiVec;
+use crate::lattices::{Lattice, LatticeRange};
+
+/*
+A predicate on a single column of a HiTable. The operand is type-erased, and
+must have the same element type as the column it is applied to.
+*/
+pub enum Predicate {
+    Equals(Box<dyn Any>),
+    Range(Box<dyn Any>),
+}
+
+impl Predicate {
+    pub fn equals<T: Any>(item: T) -> Self {
+        Predicate::Equals(Box::new(item))
+    }
+
+    pub fn range<T: Any>(range: LatticeRange<T>) -> Self {
+        Predicate::Range(Box::new(range))
+    }
+}
+
+fn operand<T: Any>(x: &dyn Any) -> &T {
+    x.downcast_ref()
+        .expect("Predicate type does not match column type")
+}
+
+/*
+Object-safe view of a column, so that HiVecs with different element types can
+be stored side by side. Mirrors HiQuery, with the predicate passed explicitly.
+*/
+pub trait ColumnQuery<const N: usize, const FANOUT: usize> {
+    fn length(&self) -> usize;
+    fn query_at(&self, pred: &Predicate, i: usize) -> bool;
+    fn hiquery(&self, pred: &Predicate, layer: usize, i: usize) -> bool;
+}
+
+impl<T: Any + Lattice + Copy, const N: usize, const FANOUT: usize> ColumnQuery<N, FANOUT>
+    for HiVec<T, N, FANOUT>
+{
+    fn length(&self) -> usize {
+        self.len()
+    }
+    fn query_at(&self, pred: &Predicate, i: usize) -> bool {
+        match pred {
+            Predicate::Equals(x) => self.query_equals(*operand(x.as_ref())).query_at(i),
+            Predicate::Range(r) => self.query_range(*operand(r.as_ref())).query_at(i),
+        }
+    }
+    fn hiquery(&self, pred: &Predicate, layer: usize, i: usize) -> bool {
+        match pred {
+            Predicate::Equals(x) => self.query_equals(*operand(x.as_ref())).hiquery(layer, i),
+            Predicate::Range(r) => self.query_range(*operand(r.as_ref())).hiquery(layer, i),
+        }
+    }
+}
+
+/*
+A set of equal-length columns, each with its own hierarchy, queried together.
+*/
+pub struct HiTable<const N: usize, const FANOUT: usize> {
+    columns: Vec<Box<dyn ColumnQuery<N, FANOUT>>>,
+}
+
+impl<const N: usize, const FANOUT: usize> Default for HiTable<N, FANOUT> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize, const FANOUT: usize> HiTable<N, FANOUT> {
+    pub fn new() -> Self {
+        HiTable {
+            columns: Vec::new(),
+        }
+    }
+
+    /*
+    Adds a column and returns its index. Panics if its length differs from the
+    columns already in the table.
+    */
+    pub fn add_column<T: Any + Lattice + Copy>(&mut self, column: HiVec<T, N, FANOUT>) -> usize {
+        if let Some(first) = self.columns.first() {
+            assert_eq!(first.length(), column.len());
+        }
+        self.columns.push(Box::new(column));
+        self.columns.len() - 1
+    }
+
+    pub fn num_columns(&self) -> usize {
+        self.columns.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.columns.first().map_or(0, |c| c.length())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn query(&self, col: usize) -> &dyn ColumnQuery<N, FANOUT> {
+        self.columns[col].as_ref()
+    }
+
+    pub fn conjunction<'a>(
+        &'a self,
+        preds: &'a [(usize, Predicate)],
+    ) -> AcrossQuery<'a, N, FANOUT> {
+        for &(col, _) in preds {
+            assert!(col < self.columns.len(), "Column index out of bounds");
+        }
+        AcrossQuery { table: self, preds }
+    }
+
+    pub fn and_across(&self, preds: &[(usize, Predicate)]) -> Vec<usize> {
+        self.conjunction(preds).iter().collect()
+    }
+}
+
+/*
+Conjunction of per-column predicates. An empty conjunction matches every row.
+*/
+pub struct AcrossQuery<'a, const N: usize, const FANOUT: usize> {
+    table: &'a HiTable<N, FANOUT>,
+    preds: &'a [(usize, Predicate)],
+}
+
+impl<'a, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT> for AcrossQuery<'a, N, FANOUT> {
+    fn length(&self) -> usize {
+        self.table.len()
+    }
+    fn query_at(&self, i: usize) -> bool {
+        self.preds
+            .iter()
+            .all(|(col, pred)| self.table.query(*col).query_at(pred, i))
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        self.preds
+            .iter()
+            .all(|(col, pred)| self.table.query(*col).hiquery(pred, layer, i))
+    }
+}
+
+impl<'a, const N: usize, const FANOUT: usize> Debug for AcrossQuery<'a, N, FANOUT> {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let columns: Vec<usize> = self.preds.iter().map(|&(col, _)| col).collect();
+        f.debug_struct("AcrossQuery")
+            .field("columns", &columns)
+            .field("length", &self.length())
+            .finish()
+    }
+}
@@ -1,59 +1,148 @@
-use crate::hiqueries::{HiQuery, NegatableQuery};
-use crate::lattices::{Lattice, LatticeRange};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::fmt::{Debug, Formatter};
+use std::hash::{Hash, Hasher};
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+use crate::hiqueries::{CausalQuery, HiQuery, NegatableQuery};
+use crate::lattices::{
+    BoundedLattice, FreeL32, FreeLattice, Keyed, Lattice, LatticeRange, SaturatingCounter,
+    Splittable, TimeTagged, TropicalCost,
+};
+
+/*
+The outer container of the layers. Hierarchies are rarely more than 4 deep, so
+with the smallvec feature the layers are kept inline up to that depth.
+*/
+#[cfg(not(feature = "smallvec"))]
+type Layers<T> = Vec<Vec<LatticeRange<T>>>;
+#[cfg(feature = "smallvec")]
+type Layers<T> = smallvec::SmallVec<[Vec<LatticeRange<T>>; 4]>;
 
 #[derive(Debug, Clone)]
 pub struct HiVec<T, const N: usize, const FANOUT: usize> {
     table: Vec<T>,
-    layers: Vec<Vec<LatticeRange<T>>>,
+    layers: Layers<T>,
 }
 
-impl<T: Copy + Lattice, const N: usize, const FANOUT: usize> HiVec<T, N, FANOUT> {
+impl<T: Clone + Lattice, const N: usize, const FANOUT: usize> HiVec<T, N, FANOUT> {
     pub fn new(table: Vec<T>) -> Self {
-        let mut layers: Vec<Vec<LatticeRange<T>>> = Vec::with_capacity(N);
-        let ranges = table
-            .chunks(FANOUT)
-            .map(|chunk| {
-                let bot = chunk
-                    .iter()
-                    .cloned()
-                    .reduce(|x, y| x.meet(y))
-                    .expect("Impossible: Empty Chunk");
-                let top = chunk
-                    .iter()
-                    .cloned()
-                    .reduce(|x, y| x.meet(y))
-                    .expect("Impossible: Empty Chunk");
-                LatticeRange::new(top, bot)
-            })
-            .collect();
-        layers.push(ranges);
+        let layers = build_layers::<T, N, FANOUT>(&table);
+        HiVec { table, layers }
+    }
+
+    /*
+    Builds the same layers as new, but in one pass over the table. Each block of
+    leaves is folded into a running range per layer, which is pushed once its block
+    is complete, so no layer is read back after it is written. Every layer is
+    allocated once, at its final size. With N == 0 there is no cascade to fold, so it
+    defers to new, which still builds the leaf ranges.
+    */
+    pub fn new_single_pass(table: Vec<T>) -> Self {
+        if N == 0 {
+            return Self::new(table);
+        }
+        let mut layers = Layers::with_capacity(N);
+        let mut blocks = table.len();
+        for _ in 0..N {
+            blocks = blocks.div_ceil(FANOUT);
+            layers.push(Vec::with_capacity(blocks));
+        }
+        let mut pending: Vec<Option<LatticeRange<T>>> = vec![None; N];
+        let unite = |acc: &mut Option<LatticeRange<T>>, r: &LatticeRange<T>| {
+            *acc = Some(match acc.take() {
+                Some(a) => a.unite(r.clone()),
+                None => r.clone(),
+            });
+        };
+        for chunk in table.chunks(FANOUT) {
+            let mut node = chunk[1..]
+                .iter()
+                .fold(LatticeRange::singleton(chunk[0].clone()), |r, x| {
+                    r.expandby(x.clone())
+                });
+            let mut l = 0;
+            loop {
+                layers[l].push(node.clone());
+                if l + 1 == N {
+                    break;
+                }
+                unite(&mut pending[l + 1], &node);
+                if !layers[l].len().is_multiple_of(FANOUT) {
+                    break;
+                }
+                node = pending[l + 1].take().expect("Impossible: no pending range");
+                l += 1;
+            }
+        }
         for l in 1..N {
-            let nextlayer = layers[l - 1]
-                .chunks(FANOUT)
-                .map(|chunk| {
-                    chunk
-                        .iter()
-                        .cloned()
-                        .reduce(|x, y| x.unite(y))
-                        .expect("Impossible: Empty Chunk")
-                })
-                .collect();
-            layers.push(nextlayer)
+            if let Some(node) = pending[l].take() {
+                if l + 1 < N {
+                    unite(&mut pending[l + 1], &node);
+                }
+                layers[l].push(node);
+            }
+        }
+        HiVec { table, layers }
+    }
+
+    pub fn from_fn(len: usize, f: impl FnMut(usize) -> T) -> Self {
+        Self::new((0..len).map(f).collect())
+    }
+
+    /*
+    Builds a HiVec from (value, count) runs. Blocks that lie within a single run
+    get their singleton range directly, so only blocks on run boundaries are
+    combined element by element.
+    */
+    pub fn from_runs(runs: Vec<(T, usize)>) -> Self {
+        let len = runs.iter().map(|&(_, count)| count).sum();
+        let mut table = Vec::with_capacity(len);
+        let mut ranges: Vec<LatticeRange<T>> = Vec::with_capacity(len.div_ceil(FANOUT));
+        for (value, mut count) in runs {
+            let offset = table.len() % FANOUT;
+            table.extend(std::iter::repeat_n(value.clone(), count));
+            if offset != 0 && count > 0 {
+                let last = ranges
+                    .last_mut()
+                    .expect("Impossible: open block without range");
+                *last = last.expandby(value.clone());
+                count -= count.min(FANOUT - offset);
+            }
+            let blocks = count.div_ceil(FANOUT);
+            ranges.extend(std::iter::repeat_n(LatticeRange::singleton(value), blocks));
         }
+        let layers = build_upper_layers::<T, N, FANOUT>(ranges);
         HiVec { table, layers }
     }
 
-    fn repair_invariant(&mut self, range: std::ops::RangeInclusive<usize>) {
+    /*
+    Builds the hierarchy over a slice owned elsewhere. The layers are derived data,
+    so they are still owned by the result.
+    */
+    pub fn new_borrowed(table: &[T]) -> HiVecRef<'_, T, N, FANOUT> {
+        HiVecRef::new(table)
+    }
+
+    fn view(&self) -> HiSlice<'_, T> {
+        HiSlice {
+            table: &self.table,
+            layers: &self.layers,
+        }
+    }
+
+    pub(crate) fn repair_invariant(&mut self, range: std::ops::RangeInclusive<usize>) {
         let range = (range.start() - range.start() % FANOUT)
-            ..=(range.end() - range.end() % FANOUT + FANOUT - 1);
+            ..=(range.end() - range.end() % FANOUT + FANOUT - 1).min(self.table.len() - 1);
         let nriter = self.table[range.clone()].chunks(FANOUT).map(|chunk| {
             chunk
                 .iter()
-                .fold(None, |r: Option<LatticeRange<T>>, &e| {
+                .fold(None, |r: Option<LatticeRange<T>>, e| {
                     Some(if let Some(r) = r {
-                        r.expandby(e)
+                        r.expandby(e.clone())
                     } else {
-                        LatticeRange::singleton(e)
+                        LatticeRange::singleton(e.clone())
                     })
                 })
                 .expect("Impossible: empty chunk")
@@ -62,91 +151,800 @@ impl<T: Copy + Lattice, const N: usize, const FANOUT: usize> HiVec<T, N, FANOUT>
         for (i, r) in nriter.enumerate() {
             self.layers[0][s + i] = r;
         }
-        let mut range = s..=((range.end() + 1) / FANOUT);
+        let mut range = s..=(range.end() / FANOUT);
         for n in 1..N {
             let (prevlayer, nextlayer) = self.layers.split_at_mut(n);
+            let prevlayer = prevlayer.last().expect("Impossible: prevlayer empty");
             range = (range.start() - range.start() % FANOUT)
-                ..=(range.end() - range.end() % FANOUT + FANOUT - 1);
-            let it = prevlayer.last().expect("Impossible: prevlayer empty")[range.clone()]
+                ..=(range.end() - range.end() % FANOUT + FANOUT - 1).min(prevlayer.len() - 1);
+            let it = prevlayer[range.clone()]
                 .chunks(FANOUT)
                 .map(|chunk| chunk.iter().cloned().reduce(|x, y| x.unite(y)).unwrap());
             let s = range.start() / FANOUT;
             for (i, r) in it.enumerate() {
                 nextlayer[0][s + i] = r;
             }
-            range = s..=((range.end() + 1) / FANOUT);
+            range = s..=(range.end() / FANOUT);
+        }
+    }
+
+    /*
+    Rebuilds every layer from the table, as new does, reusing the layers'
+    allocations. Use it after editing many positions at once.
+    */
+    pub fn recompute_all(&mut self) {
+        let (first, rest) = self.layers.split_at_mut(1);
+        first[0].clear();
+        first[0].extend(self.table.chunks(FANOUT).map(|chunk| {
+            chunk[1..]
+                .iter()
+                .fold(LatticeRange::singleton(chunk[0].clone()), |r, x| {
+                    r.expandby(x.clone())
+                })
+        }));
+        let mut prev = &first[0];
+        for layer in rest {
+            layer.clear();
+            layer.extend(prev.chunks(FANOUT).map(|chunk| {
+                chunk
+                    .iter()
+                    .cloned()
+                    .reduce(|x, y| x.unite(y))
+                    .expect("Impossible: empty chunk")
+            }));
+            prev = layer;
         }
     }
 
+    pub(crate) fn into_parts(self) -> (Vec<T>, Vec<Vec<LatticeRange<T>>>) {
+        (self.table, self.layers.into_iter().collect())
+    }
+
     pub fn len(&self) -> usize {
         self.table.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+
+    pub fn as_table(&self) -> &[T] {
+        &self.table
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        self.table.iter()
+    }
+
+    /*
+    Rebuilds the hierarchy with a different depth and fanout.
+    */
+    pub fn reshape<const N2: usize, const FANOUT2: usize>(self) -> HiVec<T, N2, FANOUT2> {
+        HiVec::new(self.table)
+    }
+
     pub fn get(&self, i: usize) -> Option<&T> {
         self.table.get(i)
     }
 
-    pub fn mutate(&mut self, i: usize, f: impl FnOnce(&mut T)) {
-        self.table.get_mut(i).map(f);
+    /*
+    Reserves room for additional more elements, in the table and in every layer,
+    so that pushing them does not reallocate.
+    */
+    pub fn reserve(&mut self, additional: usize) {
+        let target = self.table.len() + additional;
+        self.table.reserve(additional);
+        let mut span = 1usize;
+        for layer in self.layers.iter_mut() {
+            span = span.saturating_mul(FANOUT);
+            layer.reserve(target.div_ceil(span) - layer.len());
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.table.capacity()
+    }
+
+    pub fn num_layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    /*
+    The stored block ranges of layer n, numbered as in block_is_uniform.
+    */
+    pub fn layer(&self, n: usize) -> Option<&[LatticeRange<T>]> {
+        self.layers.get(n).map(|l| l.as_slice())
+    }
+
+    /*
+    Every block of the given layer whose stored range differs from the one
+    recomputed from the table, as (block, stored, recomputed). For debugging
+    stale layers; it rebuilds the whole hierarchy.
+    */
+    pub fn layer_mismatches(&self, layer: usize) -> Vec<(usize, LatticeRange<T>, LatticeRange<T>)> {
+        let fresh = build_layers::<T, N, FANOUT>(&self.table);
+        self.layers[layer]
+            .iter()
+            .zip(&fresh[layer])
+            .enumerate()
+            .filter(|(_, (stored, fresh))| stored != fresh)
+            .map(|(b, (stored, fresh))| (b, stored.clone(), fresh.clone()))
+            .collect()
+    }
+
+    /*
+    Whether every stored layer, including its length, matches the one rebuilt from
+    the table. Like layer_mismatches it rebuilds the whole hierarchy.
+    */
+    pub fn validate_invariant(&self) -> bool {
+        self.layers == build_layers::<T, N, FANOUT>(&self.table)
+    }
+
+    // Edits the table without repairing the layers, to test the diagnostics.
+    #[cfg(test)]
+    pub(crate) fn set_unrepaired(&mut self, i: usize, value: T) {
+        self.table[i] = value;
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_layer_unrepaired(&mut self, layer: usize, block: usize, r: LatticeRange<T>) {
+        self.layers[layer][block] = r;
+    }
+
+    #[cfg(test)]
+    pub(crate) fn layer_capacities(&self) -> Vec<usize> {
+        self.layers.iter().map(|l| l.capacity()).collect()
+    }
+
+    #[cfg(all(test, feature = "smallvec"))]
+    pub(crate) fn layers_inline(&self) -> bool {
+        !self.layers.spilled()
+    }
+
+    /*
+    Appends a value, updating the one block per layer that covers it.
+    Block ranges only grow on append, so each can be widened in place.
+    */
+    pub fn push(&mut self, value: T) {
+        self.table.push(value.clone());
+        let mut b = (self.table.len() - 1) / FANOUT;
+        if b == self.layers[0].len() {
+            self.layers[0].push(LatticeRange::singleton(value));
+        } else {
+            self.layers[0][b] = self.layers[0][b].expandby(value);
+        }
+        for l in 1..N {
+            let child = self.layers[l - 1][b].clone();
+            b /= FANOUT;
+            if b == self.layers[l].len() {
+                self.layers[l].push(child);
+            } else {
+                self.layers[l][b] = self.layers[l][b].clone().unite(child);
+            }
+        }
+    }
+
+    /*
+    Removes the last value. Blocks that become empty are dropped, and only the last
+    block of each layer is recomputed, since its range may shrink.
+    */
+    pub fn pop(&mut self) -> Option<T> {
+        let value = self.table.pop()?;
+        let mut blocks = self.table.len();
+        for l in 0..self.layers.len() {
+            let children = blocks;
+            blocks = children.div_ceil(FANOUT);
+            self.layers[l].truncate(blocks);
+            if blocks == 0 {
+                continue;
+            }
+            let first = (blocks - 1) * FANOUT;
+            let last = if l == 0 {
+                self.table[first..children].iter().cloned().fold(
+                    None,
+                    |r: Option<LatticeRange<T>>, e| {
+                        Some(match r {
+                            Some(r) => r.expandby(e),
+                            None => LatticeRange::singleton(e),
+                        })
+                    },
+                )
+            } else {
+                self.layers[l - 1][first..children]
+                    .iter()
+                    .cloned()
+                    .reduce(|x, y| x.unite(y))
+            };
+            self.layers[l][blocks - 1] = last.expect("Impossible: empty chunk");
+        }
+        Some(value)
+    }
+
+    /*
+    Edits the value at i, repairing the hierarchy only if f changed it. Returns
+    whether it did; out of bounds indices are ignored. Changed means != under T's
+    PartialEq, so for types whose equality ignores part of the value, such as Keyed
+    comparing only keys, an edit to the rest is not repaired: use mutate_always.
+    */
+    pub fn mutate(&mut self, i: usize, f: impl FnOnce(&mut T)) -> bool {
+        let Some(x) = self.table.get_mut(i) else {
+            return false;
+        };
+        let old = x.clone();
+        f(x);
+        let changed = *x != old;
+        if changed {
+            self.repair_invariant(i..=i);
+        }
+        changed
+    }
+
+    /*
+    Edits the value at i and always repairs, for values that are expensive to compare
+    or whose equality doesn't see every edit.
+    */
+    pub fn mutate_always(&mut self, i: usize, f: impl FnOnce(&mut T)) {
+        if let Some(x) = self.table.get_mut(i) {
+            f(x);
+            self.repair_invariant(i..=i);
+        }
+    }
+
+    /*
+    Replaces the value at i, returning the old one, or None if i is out of bounds.
+    */
+    pub fn set(&mut self, i: usize, value: T) -> Option<T> {
+        let old = std::mem::replace(self.table.get_mut(i)?, value);
         self.repair_invariant(i..=i);
+        Some(old)
+    }
+
+    /*
+    Sets several values, then repairs the hierarchy once over the span from the
+    lowest to the highest edited index. Out of bounds edits are ignored, as in mutate.
+    */
+    pub fn mutate_many(&mut self, edits: impl IntoIterator<Item = (usize, T)>) {
+        let mut span: Option<(usize, usize)> = None;
+        for (i, value) in edits {
+            if let Some(x) = self.table.get_mut(i) {
+                *x = value;
+                span = Some(span.map_or((i, i), |(lo, hi)| (lo.min(i), hi.max(i))));
+            }
+        }
+        if let Some((lo, hi)) = span {
+            self.repair_invariant(lo..=hi);
+        }
+    }
+
+    /*
+    Like mutate, but through a guard that repairs the hierarchy when dropped, and
+    only if the value was actually changed.
+    */
+    pub fn get_mut(&mut self, i: usize) -> Option<CellGuard<'_, T, N, FANOUT>> {
+        let original = self.table.get(i)?.clone();
+        Some(CellGuard {
+            hiv: self,
+            i,
+            original,
+        })
+    }
+
+    /*
+    Sum of the values at the indices matched by query, widened to i128 so it can't
+    overflow. Sums aren't a lattice operation, so the hierarchy only helps by
+    skipping blocks without matches.
+    */
+    pub fn sum_where(&self, query: &impl HiQuery<N, FANOUT>) -> i128
+    where
+        T: Into<i128>,
+    {
+        assert_eq!(query.length(), self.len());
+        query.iter().map(|i| self.table[i].clone().into()).sum()
+    }
+
+    /*
+    The values at the indices matched by query, in index order. Taking the query as
+    an argument, as sum_where does, lets it be any combination of queries on self.
+    */
+    pub fn select(&self, query: &impl HiQuery<N, FANOUT>) -> Vec<T> {
+        assert_eq!(query.length(), self.len());
+        query.iter().map(|i| self.table[i].clone()).collect()
+    }
+
+    /*
+    A cheap hash of the topmost layer's block ranges. It changes whenever a mutation
+    widens or narrows some block's range, but not for edits that keep every range
+    the same, such as swapping two values within a block.
+    */
+    pub fn content_fingerprint(&self) -> u64
+    where
+        T: Hash,
+    {
+        let mut hasher = DefaultHasher::new();
+        self.len().hash(&mut hasher);
+        self.layers.last().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /*
+    Whether all elements of the given block are equal. Layer 0 holds blocks of FANOUT
+    elements, and layer l blocks of FANOUT^(l+1). Uniform blocks are the ones where
+    equality queries prune best.
+    */
+    pub fn block_is_uniform(&self, layer: usize, block: usize) -> bool {
+        self.layers[layer][block].is_singleton()
+    }
+
+    /*
+    The blocks of the given layer for which block_is_uniform holds, in order.
+    */
+    pub fn constant_blocks(&self, layer: usize) -> impl Iterator<Item = usize> + '_ {
+        self.layers[layer]
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| r.is_singleton())
+            .map(|(b, _)| b)
+    }
+
+    /*
+    The distinct values in the table, in order of first appearance. Uniform blocks
+    are taken in one step from their range, so long runs are not scanned.
+    */
+    pub fn distinct_values(&self) -> Vec<T>
+    where
+        T: Eq + Hash,
+    {
+        let mut seen = HashSet::new();
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < self.len() {
+            let mut value = &self.table[i];
+            let mut step = 1;
+            let mut l = 0;
+            let mut j = i;
+            while l < N && j.is_multiple_of(FANOUT) && self.layers[l][j / FANOUT].is_singleton() {
+                j /= FANOUT;
+                value = self.layers[l][j].bottom();
+                l += 1;
+                step *= FANOUT;
+            }
+            if seen.insert(value) {
+                out.push(value.clone());
+            }
+            i += step;
+        }
+        out
+    }
+
+    pub fn query_equals(&self, item: T) -> EqualsQuery<'_, T, N, FANOUT> {
+        EqualsQuery {
+            item,
+            hiv: self.view(),
+        }
+    }
+
+    pub fn query_not_equals(&self, item: T) -> NotEqualsQuery<'_, T, N, FANOUT> {
+        NotEqualsQuery {
+            item,
+            hiv: self.view(),
+        }
+    }
+
+    /*
+    Threshold queries in the lattice order. For partial orders, incomparable
+    elements match neither.
+    */
+    pub fn query_le(&self, bound: T) -> LeqQuery<'_, T, N, FANOUT> {
+        LeqQuery {
+            bound,
+            hiv: self.view(),
+        }
+    }
+
+    pub fn query_ge(&self, bound: T) -> GeqQuery<'_, T, N, FANOUT> {
+        GeqQuery {
+            bound,
+            hiv: self.view(),
+        }
+    }
+
+    /*
+    Matches elements for which f holds. block_f is asked about whole blocks and
+    must over-approximate f: it may only return false for a block range if f is
+    false for every element in that range, otherwise matches are skipped.
+    */
+    pub fn query_by<F, B>(&self, f: F, block_f: B) -> PredicateQuery<'_, T, F, B, N, FANOUT>
+    where
+        F: Fn(&T) -> bool,
+        B: Fn(&LatticeRange<T>) -> bool,
+    {
+        PredicateQuery {
+            f,
+            block_f,
+            hiv: self.view(),
+        }
+    }
+
+    pub fn query_range(&self, range: LatticeRange<T>) -> RangeQuery<'_, T, N, FANOUT> {
+        RangeQuery {
+            range,
+            hiv: self.view(),
+        }
+    }
+
+    /*
+    Matches indices i > 0 whose value differs from the one at i - 1.
+    */
+    pub fn query_transitions(&self) -> TransitionsQuery<'_, T, N, FANOUT> {
+        TransitionsQuery { hiv: self.view() }
+    }
+
+    /*
+    Matches indices i with a transition somewhere in (i - k, i].
+    */
+    pub fn query_recent_change(&self, k: usize) -> RecentChangeQuery<'_, T, N, FANOUT> {
+        RecentChangeQuery {
+            k,
+            transitions: self.query_transitions(),
+        }
     }
 
-    pub fn query_equals(&self, item: T) -> EqualsQuery<T, N, FANOUT> {
-        EqualsQuery { item, hiv: self }
+    /*
+    Runs an equality query per distinct target, in order of first occurrence.
+    Equal targets are coalesced, so each is only queried once.
+    */
+    pub fn query_equals_many(&self, items: impl IntoIterator<Item = T>) -> Vec<(T, Vec<usize>)>
+    where
+        T: Eq + Hash,
+    {
+        let mut seen = HashSet::new();
+        items
+            .into_iter()
+            .filter(|x| seen.insert(x.clone()))
+            .map(|x| (x.clone(), self.query_equals(x).iter().collect()))
+            .collect()
     }
 
-    pub fn query_range(&self, range: LatticeRange<T>) -> RangeQuery<T, N, FANOUT> {
-        RangeQuery { range, hiv: self }
+    /*
+    Like query_equals and query_range, but holding a reference count on the HiVec
+    instead of a borrow, so the query is 'static and, for Send + Sync T, can be
+    moved to another thread.
+    */
+    pub fn query_equals_owned(self: &Arc<Self>, item: T) -> OwnedEqualsQuery<T, N, FANOUT> {
+        OwnedEqualsQuery {
+            item,
+            hiv: Arc::clone(self),
+        }
+    }
+
+    pub fn query_range_owned(
+        self: &Arc<Self>,
+        range: LatticeRange<T>,
+    ) -> OwnedRangeQuery<T, N, FANOUT> {
+        OwnedRangeQuery {
+            range,
+            hiv: Arc::clone(self),
+        }
     }
 }
 
-pub struct EqualsQuery<'a, T, const N: usize, const FANOUT: usize> {
-    item: T,
-    hiv: &'a HiVec<T, N, FANOUT>,
+fn chunk_range<T: Clone + Lattice>(chunk: &[T]) -> LatticeRange<T> {
+    let bot = chunk
+        .iter()
+        .cloned()
+        .reduce(|x, y| x.meet(y))
+        .expect("Impossible: Empty Chunk");
+    let top = chunk
+        .iter()
+        .cloned()
+        .reduce(|x, y| x.join(y))
+        .expect("Impossible: Empty Chunk");
+    LatticeRange::new(top, bot)
 }
 
-impl<'a, T: Lattice + Copy, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT>
-    for EqualsQuery<'a, T, N, FANOUT>
+fn united_range<T: Clone + Lattice>(chunk: &[LatticeRange<T>]) -> LatticeRange<T> {
+    chunk
+        .iter()
+        .cloned()
+        .reduce(|x, y| x.unite(y))
+        .expect("Impossible: Empty Chunk")
+}
+
+fn build_layers<T: Clone + Lattice, const N: usize, const FANOUT: usize>(table: &[T]) -> Layers<T> {
+    let ranges = table.chunks(FANOUT).map(chunk_range).collect();
+    build_upper_layers::<T, N, FANOUT>(ranges)
+}
+
+// Builds layers 1..N on top of the given layer 0.
+fn build_upper_layers<T: Clone + Lattice, const N: usize, const FANOUT: usize>(
+    ranges: Vec<LatticeRange<T>>,
+) -> Layers<T> {
+    let mut layers = Layers::with_capacity(N);
+    layers.push(ranges);
+    for l in 1..N {
+        let nextlayer = layers[l - 1].chunks(FANOUT).map(united_range).collect();
+        layers.push(nextlayer)
+    }
+    layers
+}
+
+/*
+Builds the same layers as new, with each layer computed by a parallel map over its
+blocks. The layers still depend on each other, so they are built one at a time.
+*/
+#[cfg(feature = "rayon")]
+impl<T: Clone + Lattice + Send + Sync, const N: usize, const FANOUT: usize> HiVec<T, N, FANOUT> {
+    pub fn new_parallel(table: Vec<T>) -> Self {
+        use rayon::prelude::*;
+        let mut layers = Layers::with_capacity(N);
+        layers.push(table.par_chunks(FANOUT).map(chunk_range).collect());
+        for l in 1..N {
+            let nextlayer = layers[l - 1].par_chunks(FANOUT).map(united_range).collect();
+            layers.push(nextlayer)
+        }
+        HiVec { table, layers }
+    }
+}
+
+impl<'a, T, const N: usize, const FANOUT: usize> IntoIterator for &'a HiVec<T, N, FANOUT> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.table.iter()
+    }
+}
+
+/*
+Collects into the table and builds the layers as new does. N and FANOUT can't be
+inferred from the iterator, so the target type needs an annotation, e.g.
+let hv: HiVec<_, 3, 2> = data.into_iter().collect();
+*/
+impl<T: Clone + Lattice, const N: usize, const FANOUT: usize> FromIterator<T>
+    for HiVec<T, N, FANOUT>
 {
-    fn length(&self) -> usize {
-        self.hiv.len()
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Self::new(iter.into_iter().collect())
     }
-    fn query_at(&self, i: usize) -> bool {
-        self.hiv
-            .get(i)
-            .map(|&x| x == self.item)
-            .expect("Out of bounds")
+}
+
+/*
+What the queries need from either an owned or a borrowed HiVec.
+*/
+struct HiSlice<'a, T> {
+    table: &'a [T],
+    layers: &'a [Vec<LatticeRange<T>>],
+}
+
+// By hand, since deriving would require T: Copy.
+impl<'a, T> Clone for HiSlice<'a, T> {
+    fn clone(&self) -> Self {
+        *self
     }
-    fn hiquery(&self, layer: usize, i: usize) -> bool {
-        if layer == 0 {
-            self.query_at(i)
-        } else {
-            self.hiv.layers[layer - 1][i].contains(&self.item)
-        }
+}
+
+impl<'a, T> Copy for HiSlice<'a, T> {}
+
+impl<'a, T> HiSlice<'a, T> {
+    fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    fn get(&self, i: usize) -> Option<&'a T> {
+        self.table.get(i)
     }
 }
 
-// Todo: implement negation properly for more types.
+/*
+Mutable access to one element of a HiVec, see HiVec::get_mut.
+*/
+pub struct CellGuard<'a, T: Clone + Lattice, const N: usize, const FANOUT: usize> {
+    hiv: &'a mut HiVec<T, N, FANOUT>,
+    i: usize,
+    original: T,
+}
 
-impl<'a, const N: usize, const FANOUT: usize> NegatableQuery<N, FANOUT>
-    for EqualsQuery<'a, bool, N, FANOUT>
+impl<'a, T: Clone + Lattice, const N: usize, const FANOUT: usize> Deref
+    for CellGuard<'a, T, N, FANOUT>
 {
-    type NegType = Self;
-    fn negation(self: &std::sync::Arc<Self>) -> Self::NegType {
-        Self {
-            item: !self.item,
-            hiv: self.hiv,
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.hiv.table[self.i]
+    }
+}
+
+impl<'a, T: Clone + Lattice, const N: usize, const FANOUT: usize> DerefMut
+    for CellGuard<'a, T, N, FANOUT>
+{
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.hiv.table[self.i]
+    }
+}
+
+impl<'a, T: Clone + Lattice, const N: usize, const FANOUT: usize> Drop
+    for CellGuard<'a, T, N, FANOUT>
+{
+    fn drop(&mut self) {
+        if self.hiv.table[self.i] != self.original {
+            self.hiv.repair_invariant(self.i..=self.i);
         }
     }
 }
 
-pub struct RangeQuery<'a, T, const N: usize, const FANOUT: usize> {
-    range: LatticeRange<T>,
-    hiv: &'a HiVec<T, N, FANOUT>,
+/*
+A HiVec over a borrowed table, see HiVec::new_borrowed.
+*/
+#[derive(Debug, Clone)]
+pub struct HiVecRef<'a, T, const N: usize, const FANOUT: usize> {
+    table: &'a [T],
+    layers: Layers<T>,
 }
 
-impl<'a, T: Lattice + Copy, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT>
-    for RangeQuery<'a, T, N, FANOUT>
+impl<'a, T: Clone + Lattice, const N: usize, const FANOUT: usize> HiVecRef<'a, T, N, FANOUT> {
+    pub fn new(table: &'a [T]) -> Self {
+        HiVecRef {
+            table,
+            layers: build_layers::<T, N, FANOUT>(table),
+        }
+    }
+
+    fn view(&self) -> HiSlice<'_, T> {
+        HiSlice {
+            table: self.table,
+            layers: &self.layers,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+
+    pub fn get(&self, i: usize) -> Option<&'a T> {
+        self.table.get(i)
+    }
+
+    pub fn as_table(&self) -> &'a [T] {
+        self.table
+    }
+
+    pub fn query_equals(&self, item: T) -> EqualsQuery<'_, T, N, FANOUT> {
+        EqualsQuery {
+            item,
+            hiv: self.view(),
+        }
+    }
+
+    pub fn query_range(&self, range: LatticeRange<T>) -> RangeQuery<'_, T, N, FANOUT> {
+        RangeQuery {
+            range,
+            hiv: self.view(),
+        }
+    }
+}
+
+impl<T: Clone + Splittable, const N: usize, const FANOUT: usize> HiVec<T, N, FANOUT> {
+    /*
+    Indices of the elements within range, ordered by value and then by index.
+    The range is halved recursively, and a half is dropped as soon as the hierarchy
+    shows it has no matches, so only values that actually occur get enumerated.
+    */
+    pub fn query_between(&self, range: LatticeRange<T>) -> Vec<usize> {
+        let mut out = Vec::new();
+        let mut pending = vec![range];
+        while let Some(r) = pending.pop() {
+            let q = self.query_range(r.clone());
+            if q.findnext(0).is_none() {
+                continue;
+            }
+            match r.split() {
+                Some((lower, upper)) => {
+                    pending.push(upper);
+                    pending.push(lower);
+                }
+                None => out.extend(q.iter()),
+            }
+        }
+        out
+    }
+}
+
+/*
+A boolean column that keeps track of whether it holds any true value as it is
+appended to or mutated. Per block that is just the top of the block's range,
+which push and mutate already maintain.
+*/
+#[derive(Debug, Clone)]
+pub struct RunningAny<const N: usize, const FANOUT: usize> {
+    hiv: HiVec<bool, N, FANOUT>,
+    trues: usize,
+}
+
+impl<const N: usize, const FANOUT: usize> RunningAny<N, FANOUT> {
+    pub fn new(table: Vec<bool>) -> Self {
+        let trues = table.iter().filter(|&&x| x).count();
+        RunningAny {
+            hiv: HiVec::new(table),
+            trues,
+        }
+    }
+
+    pub fn as_hivec(&self) -> &HiVec<bool, N, FANOUT> {
+        &self.hiv
+    }
+
+    pub fn any(&self) -> bool {
+        self.trues > 0
+    }
+
+    pub fn count_true(&self) -> usize {
+        self.trues
+    }
+
+    /*
+    Whether block i of the given layer holds a true value.
+    */
+    pub fn block_any(&self, layer: usize, i: usize) -> bool {
+        *self.hiv.layers[layer][i].top()
+    }
+
+    pub fn push(&mut self, value: bool) {
+        self.trues += value as usize;
+        self.hiv.push(value);
+    }
+
+    pub fn mutate(&mut self, i: usize, f: impl FnOnce(&mut bool)) {
+        let Some(&old) = self.hiv.get(i) else {
+            return;
+        };
+        self.hiv.mutate(i, f);
+        let new = self.hiv.get(i) == Some(&true);
+        self.trues = self.trues + new as usize - old as usize;
+    }
+}
+
+/*
+Whether two HiVecs hold the same elements, regardless of the shape of their hierarchies.
+*/
+pub fn same_data<T, const N1: usize, const F1: usize, const N2: usize, const F2: usize>(
+    a: &HiVec<T, N1, F1>,
+    b: &HiVec<T, N2, F2>,
+) -> bool
+where
+    T: Clone + Lattice,
+{
+    a.as_table() == b.as_table()
+}
+
+/*
+Whether equality queries for each of the sample values give the same results on both HiVecs.
+Unlike same_data this goes through the hierarchies, so it also catches inconsistent layers.
+*/
+pub fn same_queries<T, const N1: usize, const F1: usize, const N2: usize, const F2: usize>(
+    a: &HiVec<T, N1, F1>,
+    b: &HiVec<T, N2, F2>,
+    samples: &[T],
+) -> bool
+where
+    T: Clone + Lattice,
+{
+    a.len() == b.len()
+        && samples.iter().all(|x| {
+            a.query_equals(x.clone())
+                .iter()
+                .eq(b.query_equals(x.clone()).iter())
+        })
+}
+
+pub struct EqualsQuery<'a, T, const N: usize, const FANOUT: usize> {
+    item: T,
+    hiv: HiSlice<'a, T>,
+}
+
+impl<'a, T: Lattice + Clone, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT>
+    for EqualsQuery<'a, T, N, FANOUT>
 {
     fn length(&self) -> usize {
         self.hiv.len()
@@ -154,7 +952,115 @@ impl<'a, T: Lattice + Copy, const N: usize, const FANOUT: usize> HiQuery<N, FANO
     fn query_at(&self, i: usize) -> bool {
         self.hiv
             .get(i)
-            .map(|x| self.range.contains(x))
+            .map(|x| *x == self.item)
+            .expect("Out of bounds")
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            self.hiv.layers[layer - 1][i].contains(&self.item)
+        }
+    }
+    fn hiquery_all(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            let r = &self.hiv.layers[layer - 1][i];
+            r.is_singleton() && *r.top() == self.item
+        }
+    }
+}
+
+impl<'a, T: Lattice + Clone, const N: usize, const FANOUT: usize> CausalQuery<T, N, FANOUT>
+    for EqualsQuery<'a, T, N, FANOUT>
+{
+    fn cause(&self, i: usize) -> Option<&T> {
+        self.hiv.get(i).filter(|x| **x == self.item)
+    }
+}
+
+/*
+A block may hold a value other than item unless its range is exactly {item}.
+*/
+pub struct NotEqualsQuery<'a, T, const N: usize, const FANOUT: usize> {
+    item: T,
+    hiv: HiSlice<'a, T>,
+}
+
+impl<'a, T: Lattice + Clone, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT>
+    for NotEqualsQuery<'a, T, N, FANOUT>
+{
+    fn length(&self) -> usize {
+        self.hiv.len()
+    }
+    fn query_at(&self, i: usize) -> bool {
+        self.hiv
+            .get(i)
+            .map(|x| *x != self.item)
+            .expect("Out of bounds")
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            let r = &self.hiv.layers[layer - 1][i];
+            !(r.is_singleton() && *r.top() == self.item)
+        }
+    }
+    fn hiquery_all(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            !self.hiv.layers[layer - 1][i].contains(&self.item)
+        }
+    }
+}
+
+/*
+Negating a leaf must not negate hiquery itself: a block can hold both matching
+and non-matching elements, so the negated block test asks whether the block has
+any non-matching element, which is the negation of hiquery_all.
+*/
+impl<'a, T: Lattice + Clone, const N: usize, const FANOUT: usize> NegatableQuery<N, FANOUT>
+    for EqualsQuery<'a, T, N, FANOUT>
+{
+    type NegType = NotEqualsQuery<'a, T, N, FANOUT>;
+    fn negation(self: &std::sync::Arc<Self>) -> Self::NegType {
+        NotEqualsQuery {
+            item: self.item.clone(),
+            hiv: self.hiv,
+        }
+    }
+}
+
+impl<'a, T: Lattice + Clone, const N: usize, const FANOUT: usize> NegatableQuery<N, FANOUT>
+    for NotEqualsQuery<'a, T, N, FANOUT>
+{
+    type NegType = EqualsQuery<'a, T, N, FANOUT>;
+    fn negation(self: &std::sync::Arc<Self>) -> Self::NegType {
+        EqualsQuery {
+            item: self.item.clone(),
+            hiv: self.hiv,
+        }
+    }
+}
+
+pub struct RangeQuery<'a, T, const N: usize, const FANOUT: usize> {
+    range: LatticeRange<T>,
+    hiv: HiSlice<'a, T>,
+}
+
+impl<'a, T: Lattice + Clone, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT>
+    for RangeQuery<'a, T, N, FANOUT>
+{
+    fn length(&self) -> usize {
+        self.hiv.len()
+    }
+    fn query_at(&self, i: usize) -> bool {
+        self.hiv
+            .get(i)
+            .map(|x| self.range.contains(x))
             .expect("Out of bounds")
     }
     fn hiquery(&self, layer: usize, i: usize) -> bool {
@@ -162,8 +1068,592 @@ impl<'a, T: Lattice + Copy, const N: usize, const FANOUT: usize> HiQuery<N, FANO
             self.query_at(i)
         } else {
             !(self.hiv.layers[layer - 1][i]
-                .intersect(self.range)
+                .clone()
+                .intersect(self.range.clone())
                 .isempty())
         }
     }
+    fn hiquery_all(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            let r = &self.hiv.layers[layer - 1][i];
+            self.range.contains(r.top()) && self.range.contains(r.bottom())
+        }
+    }
+}
+
+impl<'a, T: Lattice + Clone, const N: usize, const FANOUT: usize> CausalQuery<T, N, FANOUT>
+    for RangeQuery<'a, T, N, FANOUT>
+{
+    fn cause(&self, i: usize) -> Option<&T> {
+        self.hiv.get(i).filter(|x| self.range.contains(x))
+    }
+}
+
+impl<'a, T: Lattice + Clone, const N: usize, const FANOUT: usize> NegatableQuery<N, FANOUT>
+    for RangeQuery<'a, T, N, FANOUT>
+{
+    type NegType = OutsideRangeQuery<'a, T, N, FANOUT>;
+    fn negation(self: &std::sync::Arc<Self>) -> Self::NegType {
+        OutsideRangeQuery {
+            range: self.range.clone(),
+            hiv: self.hiv,
+        }
+    }
+}
+
+/*
+The complement of a RangeQuery. A block may hold an element outside the range
+unless both of its bounds lie inside it, and holds only such elements when its
+range does not meet the query range at all.
+*/
+pub struct OutsideRangeQuery<'a, T, const N: usize, const FANOUT: usize> {
+    range: LatticeRange<T>,
+    hiv: HiSlice<'a, T>,
+}
+
+impl<'a, T: Lattice + Clone, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT>
+    for OutsideRangeQuery<'a, T, N, FANOUT>
+{
+    fn length(&self) -> usize {
+        self.hiv.len()
+    }
+    fn query_at(&self, i: usize) -> bool {
+        self.hiv
+            .get(i)
+            .map(|x| !self.range.contains(x))
+            .expect("Out of bounds")
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            let r = &self.hiv.layers[layer - 1][i];
+            !(self.range.contains(r.top()) && self.range.contains(r.bottom()))
+        }
+    }
+    fn hiquery_all(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            self.hiv.layers[layer - 1][i]
+                .clone()
+                .intersect(self.range.clone())
+                .isempty()
+        }
+    }
+}
+
+impl<'a, T: Lattice + Clone, const N: usize, const FANOUT: usize> NegatableQuery<N, FANOUT>
+    for OutsideRangeQuery<'a, T, N, FANOUT>
+{
+    type NegType = RangeQuery<'a, T, N, FANOUT>;
+    fn negation(self: &std::sync::Arc<Self>) -> Self::NegType {
+        RangeQuery {
+            range: self.range.clone(),
+            hiv: self.hiv,
+        }
+    }
+}
+
+pub struct PredicateQuery<'a, T, F, B, const N: usize, const FANOUT: usize> {
+    f: F,
+    block_f: B,
+    hiv: HiSlice<'a, T>,
+}
+
+impl<'a, T, F, B, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT>
+    for PredicateQuery<'a, T, F, B, N, FANOUT>
+where
+    T: Lattice + Clone,
+    F: Fn(&T) -> bool,
+    B: Fn(&LatticeRange<T>) -> bool,
+{
+    fn length(&self) -> usize {
+        self.hiv.len()
+    }
+    fn query_at(&self, i: usize) -> bool {
+        self.hiv.get(i).map(&self.f).expect("Out of bounds")
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            (self.block_f)(&self.hiv.layers[layer - 1][i])
+        }
+    }
+}
+
+/*
+A block has no transitions when its range is a singleton that also equals the
+element just before it, so stable runs are skipped a block at a time.
+*/
+pub struct TransitionsQuery<'a, T, const N: usize, const FANOUT: usize> {
+    hiv: HiSlice<'a, T>,
+}
+
+impl<'a, T: Lattice + Clone, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT>
+    for TransitionsQuery<'a, T, N, FANOUT>
+{
+    fn length(&self) -> usize {
+        self.hiv.len()
+    }
+    fn query_at(&self, i: usize) -> bool {
+        let x = self.hiv.get(i).expect("Out of bounds");
+        i > 0 && self.hiv.get(i - 1) != Some(x)
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            let r = &self.hiv.layers[layer - 1][i];
+            let start = i * FANOUT.saturating_pow(layer as u32);
+            !(r.is_singleton() && (start == 0 || self.hiv.get(start - 1) == Some(r.top())))
+        }
+    }
+}
+
+/*
+Finds the last transition at or before the end of a span with findprev, so a
+block is only visited when a transition is within k of it.
+*/
+pub struct RecentChangeQuery<'a, T, const N: usize, const FANOUT: usize> {
+    k: usize,
+    transitions: TransitionsQuery<'a, T, N, FANOUT>,
+}
+
+impl<'a, T: Lattice + Clone, const N: usize, const FANOUT: usize>
+    RecentChangeQuery<'a, T, N, FANOUT>
+{
+    // Whether a transition lies in (start - k, end].
+    fn changed_within(&self, start: usize, end: usize) -> bool {
+        self.transitions
+            .findprev(end)
+            .is_some_and(|j| j + self.k > start)
+    }
+}
+
+impl<'a, T: Lattice + Clone, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT>
+    for RecentChangeQuery<'a, T, N, FANOUT>
+{
+    fn length(&self) -> usize {
+        self.transitions.length()
+    }
+    fn query_at(&self, i: usize) -> bool {
+        assert!(i < self.length(), "Out of bounds");
+        self.changed_within(i, i)
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        let size = FANOUT.saturating_pow(layer as u32);
+        let start = i * size;
+        let end = start.saturating_add(size).min(self.length()) - 1;
+        self.changed_within(start, end)
+    }
+}
+
+/*
+Matches elements >= bound. Any such element is below its block's top, so a
+block can be skipped when its top is not >= bound.
+*/
+pub struct GeqQuery<'a, T, const N: usize, const FANOUT: usize> {
+    bound: T,
+    hiv: HiSlice<'a, T>,
+}
+
+impl<'a, T: Lattice + Clone, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT>
+    for GeqQuery<'a, T, N, FANOUT>
+{
+    fn length(&self) -> usize {
+        self.hiv.len()
+    }
+    fn query_at(&self, i: usize) -> bool {
+        self.hiv
+            .get(i)
+            .map(|x| *x >= self.bound)
+            .expect("Out of bounds")
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            *self.hiv.layers[layer - 1][i].top() >= self.bound
+        }
+    }
+    fn hiquery_all(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            *self.hiv.layers[layer - 1][i].bottom() >= self.bound
+        }
+    }
+}
+
+impl<K: Lattice + Clone, P: Clone, const N: usize, const FANOUT: usize>
+    HiVec<Keyed<K, P>, N, FANOUT>
+{
+    /*
+    Matches rows whose key equals key, whatever their payload.
+    */
+    pub fn query_equals_key(&self, key: K) -> KeyEqualsQuery<'_, K, P, N, FANOUT> {
+        KeyEqualsQuery {
+            key,
+            hiv: self.view(),
+        }
+    }
+
+    pub fn payload(&self, i: usize) -> Option<&P> {
+        self.get(i).map(|x| &x.payload)
+    }
+}
+
+pub struct KeyEqualsQuery<'a, K, P, const N: usize, const FANOUT: usize> {
+    key: K,
+    hiv: HiSlice<'a, Keyed<K, P>>,
+}
+
+impl<'a, K: Lattice + Clone, P: Clone, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT>
+    for KeyEqualsQuery<'a, K, P, N, FANOUT>
+{
+    fn length(&self) -> usize {
+        self.hiv.len()
+    }
+    fn query_at(&self, i: usize) -> bool {
+        self.hiv
+            .get(i)
+            .map(|x| x.key == self.key)
+            .expect("Out of bounds")
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            let r = &self.hiv.layers[layer - 1][i];
+            r.top().key >= self.key && self.key >= r.bottom().key
+        }
+    }
+}
+
+/*
+Matches elements <= bound, the dual of GeqQuery: a block can be skipped when its
+bottom is not <= bound.
+*/
+pub struct LeqQuery<'a, T, const N: usize, const FANOUT: usize> {
+    bound: T,
+    hiv: HiSlice<'a, T>,
+}
+
+impl<'a, T: Lattice + Clone, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT>
+    for LeqQuery<'a, T, N, FANOUT>
+{
+    fn length(&self) -> usize {
+        self.hiv.len()
+    }
+    fn query_at(&self, i: usize) -> bool {
+        self.hiv
+            .get(i)
+            .map(|x| *x <= self.bound)
+            .expect("Out of bounds")
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            *self.hiv.layers[layer - 1][i].bottom() <= self.bound
+        }
+    }
+    fn hiquery_all(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            *self.hiv.layers[layer - 1][i].top() <= self.bound
+        }
+    }
+}
+
+impl<const N: usize, const FANOUT: usize> HiVec<TropicalCost, N, FANOUT> {
+    /*
+    Matches rows whose cost is at most max_cost.
+    */
+    pub fn query_cost_leq(&self, max_cost: TropicalCost) -> LeqQuery<'_, TropicalCost, N, FANOUT> {
+        LeqQuery {
+            bound: max_cost,
+            hiv: self.view(),
+        }
+    }
+}
+
+impl<const CAP: u32, const N: usize, const FANOUT: usize> HiVec<SaturatingCounter<CAP>, N, FANOUT> {
+    /*
+    Matches counters that have reached threshold. Like the counters themselves the
+    threshold saturates, so anything above CAP matches the counters stuck at CAP.
+    */
+    pub fn query_geq(&self, threshold: u32) -> GeqQuery<'_, SaturatingCounter<CAP>, N, FANOUT> {
+        GeqQuery {
+            bound: SaturatingCounter::new(threshold),
+            hiv: self.view(),
+        }
+    }
+
+    pub fn saturated(&self) -> GeqQuery<'_, SaturatingCounter<CAP>, N, FANOUT> {
+        GeqQuery {
+            bound: SaturatingCounter::TOP,
+            hiv: self.view(),
+        }
+    }
+}
+
+impl<T: Clone + FreeLattice, const N: usize, const FANOUT: usize> HiVec<T, N, FANOUT> {
+    /*
+    Bounds on the popcount of every set in a block, with layers numbered as in
+    block_is_uniform. Each set contains the block's bottom and is contained in its
+    top, so their popcounts are the (min, max) bounds that bitset queries prune on.
+    */
+    pub fn popcount_bounds(&self, layer: usize, block: usize) -> (u32, u32) {
+        let r = self.layers[layer][block];
+        (r.bottom().popcount(), r.top().popcount())
+    }
+}
+
+impl<const N: usize, const FANOUT: usize> HiVec<FreeL32, N, FANOUT> {
+    /*
+    Matches rows whose set contains every element of subset.
+    */
+    pub fn query_contains(&self, subset: FreeL32) -> GeqQuery<'_, FreeL32, N, FANOUT> {
+        GeqQuery {
+            bound: subset,
+            hiv: self.view(),
+        }
+    }
+
+    /*
+    Matches rows whose set, intersected with mask, has between lo and hi elements.
+    */
+    pub fn query_masked_popcount_between(
+        &self,
+        mask: FreeL32,
+        lo: u32,
+        hi: u32,
+    ) -> MaskedPopcountQuery<'_, N, FANOUT> {
+        MaskedPopcountQuery {
+            mask,
+            lo,
+            hi,
+            hiv: self.view(),
+        }
+    }
+}
+
+/*
+Every set x in a block satisfies bottom <= x <= top. Meeting with the mask preserves
+this, so popcount(bottom & mask) and popcount(top & mask) bound popcount(x & mask)
+from below and above, and a block can be skipped when those bounds miss [lo, hi].
+*/
+pub struct MaskedPopcountQuery<'a, const N: usize, const FANOUT: usize> {
+    mask: FreeL32,
+    lo: u32,
+    hi: u32,
+    hiv: HiSlice<'a, FreeL32>,
+}
+
+impl<'a, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT>
+    for MaskedPopcountQuery<'a, N, FANOUT>
+{
+    fn length(&self) -> usize {
+        self.hiv.len()
+    }
+    fn query_at(&self, i: usize) -> bool {
+        let x = self.hiv.get(i).expect("Out of bounds");
+        let n = x.meet(self.mask).popcount();
+        self.lo <= n && n <= self.hi
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            let r = self.hiv.layers[layer - 1][i];
+            let min = r.bottom().meet(self.mask).popcount();
+            let max = r.top().meet(self.mask).popcount();
+            min <= self.hi && self.lo <= max
+        }
+    }
+}
+
+impl<const N: usize, const FANOUT: usize> HiVec<TimeTagged, N, FANOUT> {
+    /*
+    Matches rows whose time is in time_range and whose tags include required_tags.
+    */
+    pub fn query_time_and_tags(
+        &self,
+        time_range: LatticeRange<i64>,
+        required_tags: FreeL32,
+    ) -> TimeTagQuery<'_, N, FANOUT> {
+        TimeTagQuery {
+            time_range,
+            required_tags,
+            hiv: self.view(),
+        }
+    }
+}
+
+/*
+A block can be skipped when its times miss time_range, or when even the union
+of its tags lacks one of required_tags.
+*/
+pub struct TimeTagQuery<'a, const N: usize, const FANOUT: usize> {
+    time_range: LatticeRange<i64>,
+    required_tags: FreeL32,
+    hiv: HiSlice<'a, TimeTagged>,
+}
+
+impl<'a, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT> for TimeTagQuery<'a, N, FANOUT> {
+    fn length(&self) -> usize {
+        self.hiv.len()
+    }
+    fn query_at(&self, i: usize) -> bool {
+        let x = self.hiv.get(i).expect("Out of bounds");
+        self.time_range.contains(&x.time()) && x.tags() >= self.required_tags
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            let r = self.hiv.layers[layer - 1][i];
+            let times = LatticeRange::new(r.top().time(), r.bottom().time());
+            !times.intersect(self.time_range).isempty() && r.top().tags() >= self.required_tags
+        }
+    }
+}
+
+pub struct OwnedEqualsQuery<T, const N: usize, const FANOUT: usize> {
+    item: T,
+    hiv: Arc<HiVec<T, N, FANOUT>>,
+}
+
+impl<T: Lattice + Clone, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT>
+    for OwnedEqualsQuery<T, N, FANOUT>
+{
+    fn length(&self) -> usize {
+        self.hiv.len()
+    }
+    fn query_at(&self, i: usize) -> bool {
+        self.hiv.table[i] == self.item
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            self.hiv.layers[layer - 1][i].contains(&self.item)
+        }
+    }
+    fn hiquery_all(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            let r = &self.hiv.layers[layer - 1][i];
+            r.is_singleton() && *r.top() == self.item
+        }
+    }
+}
+
+pub struct OwnedRangeQuery<T, const N: usize, const FANOUT: usize> {
+    range: LatticeRange<T>,
+    hiv: Arc<HiVec<T, N, FANOUT>>,
+}
+
+impl<T: Lattice + Clone, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT>
+    for OwnedRangeQuery<T, N, FANOUT>
+{
+    fn length(&self) -> usize {
+        self.hiv.len()
+    }
+    fn query_at(&self, i: usize) -> bool {
+        self.range.contains(&self.hiv.table[i])
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            !(self.hiv.layers[layer - 1][i]
+                .clone()
+                .intersect(self.range.clone())
+                .isempty())
+        }
+    }
+    fn hiquery_all(&self, layer: usize, i: usize) -> bool {
+        if layer == 0 {
+            self.query_at(i)
+        } else {
+            let r = &self.hiv.layers[layer - 1][i];
+            self.range.contains(r.top()) && self.range.contains(r.bottom())
+        }
+    }
+}
+
+// The Debug impls below show the operand and length, but not the whole HiVec.
+
+impl<'a, T: Lattice + Clone + Debug, const N: usize, const FANOUT: usize> Debug
+    for EqualsQuery<'a, T, N, FANOUT>
+{
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_struct("EqualsQuery")
+            .field("item", &self.item)
+            .field("length", &self.length())
+            .finish()
+    }
+}
+
+impl<'a, T: Lattice + Clone + Debug, const N: usize, const FANOUT: usize> Debug
+    for RangeQuery<'a, T, N, FANOUT>
+{
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_struct("RangeQuery")
+            .field("range", &self.range)
+            .field("length", &self.length())
+            .finish()
+    }
+}
+
+impl<'a, T: Lattice + Clone + Debug, const N: usize, const FANOUT: usize> Debug
+    for GeqQuery<'a, T, N, FANOUT>
+{
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_struct("GeqQuery")
+            .field("bound", &self.bound)
+            .field("length", &self.length())
+            .finish()
+    }
+}
+
+impl<'a, T: Lattice + Clone + Debug, const N: usize, const FANOUT: usize> Debug
+    for LeqQuery<'a, T, N, FANOUT>
+{
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_struct("LeqQuery")
+            .field("bound", &self.bound)
+            .field("length", &self.length())
+            .finish()
+    }
+}
+
+impl<T: Lattice + Clone + Debug, const N: usize, const FANOUT: usize> Debug
+    for OwnedEqualsQuery<T, N, FANOUT>
+{
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_struct("OwnedEqualsQuery")
+            .field("item", &self.item)
+            .field("length", &self.length())
+            .finish()
+    }
+}
+
+impl<T: Lattice + Clone + Debug, const N: usize, const FANOUT: usize> Debug
+    for OwnedRangeQuery<T, N, FANOUT>
+{
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_struct("OwnedRangeQuery")
+            .field("range", &self.range)
+            .field("length", &self.length())
+            .finish()
+    }
 }
@@ -0,0 +1,73 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::hiqueries::HiQuery;
+
+/*
+Wraps a query and counts the calls made to query_at and hiquery, to check how
+much of the column a traversal actually touches.
+*/
+#[derive(Debug)]
+pub struct InstrumentedQuery<Q> {
+    q: Q,
+    query_at_calls: AtomicUsize,
+    hiquery_calls: AtomicUsize,
+}
+
+impl<Q> InstrumentedQuery<Q> {
+    pub fn new(q: Q) -> Self {
+        InstrumentedQuery {
+            q,
+            query_at_calls: AtomicUsize::new(0),
+            hiquery_calls: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn inner(&self) -> &Q {
+        &self.q
+    }
+
+    /*
+    The number of (query_at, hiquery) calls since construction or the last reset.
+    */
+    pub fn probe_counts(&self) -> (usize, usize) {
+        (
+            self.query_at_calls.load(Ordering::Relaxed),
+            self.hiquery_calls.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn reset(&self) {
+        self.query_at_calls.store(0, Ordering::Relaxed);
+        self.hiquery_calls.store(0, Ordering::Relaxed);
+    }
+}
+
+impl<Q: HiQuery<N, FANOUT>, const N: usize, const FANOUT: usize> HiQuery<N, FANOUT>
+    for InstrumentedQuery<Q>
+{
+    fn length(&self) -> usize {
+        self.q.length()
+    }
+    fn query_at(&self, i: usize) -> bool {
+        self.query_at_calls.fetch_add(1, Ordering::Relaxed);
+        self.q.query_at(i)
+    }
+    fn hiquery(&self, layer: usize, i: usize) -> bool {
+        self.hiquery_calls.fetch_add(1, Ordering::Relaxed);
+        self.q.hiquery(layer, i)
+    }
+    fn hiquery_all(&self, layer: usize, i: usize) -> bool {
+        self.hiquery_calls.fetch_add(1, Ordering::Relaxed);
+        self.q.hiquery_all(layer, i)
+    }
+    fn block_has_nonmatch(&self, layer: usize, i: usize) -> bool {
+        self.hiquery_calls.fetch_add(1, Ordering::Relaxed);
+        self.q.block_has_nonmatch(layer, i)
+    }
+    fn linear_scan_threshold(&self) -> usize {
+        self.q.linear_scan_threshold()
+    }
+    fn explain(&self, i: usize) -> String {
+        self.q.explain(i)
+    }
+}
@@ -1,4 +1,5 @@
 use std::cmp::{max, min, Ordering};
+use std::collections::BTreeSet;
 use std::fmt::{Formatter, Write};
 
 pub trait Lattice: PartialOrd {
@@ -20,7 +21,8 @@ impl<T: Ord> Lattice for T {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LatticeRange<T> {
     top: T,
     bottom: T,
@@ -39,11 +41,38 @@ impl<T: Lattice> LatticeRange<T> {
             bottom: x,
         }
     }
+    pub fn top(&self) -> &T {
+        &self.top
+    }
+    pub fn bottom(&self) -> &T {
+        &self.bottom
+    }
     pub fn isempty(&self) -> bool {
-        self.top >= self.bottom
+        !matches!(
+            self.top.partial_cmp(&self.bottom),
+            Some(Ordering::Greater | Ordering::Equal)
+        )
+    }
+    /*
+    Whether every element in the range is equal, i.e. top == bottom.
+    */
+    pub fn is_singleton(&self) -> bool {
+        self.top == self.bottom
     }
     pub fn contains(&self, x: &T) -> bool {
-        self.top >= *x && self.bottom >= *x
+        self.top >= *x && *x >= self.bottom
+    }
+    /*
+    The element of the range closest to x: (x meet top) join bottom. For a total
+    order this is the usual clamp. In general it is a projection onto the range
+    that leaves its members fixed, but an x incomparable to the bounds is moved
+    to some member that need not be comparable to x either.
+    */
+    pub fn clamp(&self, x: T) -> T
+    where
+        T: Clone,
+    {
+        x.meet(self.top.clone()).join(self.bottom.clone())
     }
     pub fn expandby(&self, x: T) -> Self
     where
@@ -68,127 +97,585 @@ impl<T: Lattice> LatticeRange<T> {
     }
 }
 
-// impl<T : Lattice> PartialOrd<T> for LatticeRange<T> {
-//     fn partial_cmp(&self, other : &Self) -> Option<Ordering> {
+/*
+Totally ordered lattices whose ranges can be cut in half, like the integers.
+*/
+pub trait Splittable: Ord + Sized {
+    /*
+    For bottom < top, returns (mid, next) such that [bottom, mid] and [next, top]
+    partition [bottom, top]. Returns None when there is nothing left to split.
+    */
+    fn split_point(bottom: &Self, top: &Self) -> Option<(Self, Self)>;
+}
 
-//     }
-// }
+macro_rules! impl_splittable {
+    ($($t:ty),*) => {
+        $(
+            impl Splittable for $t {
+                fn split_point(bottom: &Self, top: &Self) -> Option<(Self, Self)> {
+                    if bottom >= top {
+                        return None;
+                    }
+                    // Floor of the average, without overflowing.
+                    let mid = (bottom & top) + ((bottom ^ top) >> 1);
+                    Some((mid, mid + 1))
+                }
+            }
+        )*
+    };
+}
 
-// impl<T : Lattice> Lattice for LatticeRange<T> {
-//     fn meet(self, other : Self) -> Self {self.intersect(other)}
-//     fn join(self, other : Self) -> Self {self.unite(other)}
-// }
+impl_splittable!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub struct FreeL32 {
-    pub val: u32,
+impl<T: Splittable + Clone> LatticeRange<T> {
+    /*
+    Splits the range into a lower and an upper half, or None for a singleton.
+    */
+    pub fn split(&self) -> Option<(LatticeRange<T>, LatticeRange<T>)> {
+        let (mid, next) = T::split_point(&self.bottom, &self.top)?;
+        Some((
+            LatticeRange::new(mid, self.bottom.clone()),
+            LatticeRange::new(self.top.clone(), next),
+        ))
+    }
 }
 
-impl FreeL32 {
-    pub fn new(i: u32) -> Self {
-        Self { val: i }
+/*
+Ranges ordered by inclusion: a <= b when a's bounds lie within b's. Join and
+meet are then unite and intersect. The order compares bounds, so two distinct
+empty ranges are not considered equal.
+*/
+impl<T: Lattice> PartialOrd for LatticeRange<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        product_cmp(
+            self.top.partial_cmp(&other.top),
+            other.bottom.partial_cmp(&self.bottom),
+        )
     }
+}
 
-    pub fn generator(i: usize) -> Self {
-        Self { val: 1 << i }
+impl<T: Lattice> Lattice for LatticeRange<T> {
+    fn meet(self, other: Self) -> Self {
+        self.intersect(other)
     }
+    fn join(self, other: Self) -> Self {
+        self.unite(other)
+    }
+}
 
-    pub fn complement(&self) -> Self {
-        Self { val: !self.val }
+// The unit type is already a lattice through its Ord impl.
+impl BoundedLattice for () {
+    const TOP: Self = ();
+    const BOT: Self = ();
+}
+
+/*
+The one-element lattice, where TOP == BOT. Handy for instantiating generic code.
+*/
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Singleton;
+
+impl BoundedLattice for Singleton {
+    const TOP: Self = Singleton;
+    const BOT: Self = Singleton;
+}
+
+/*
+The product of lattices, ordered componentwise. It wraps a tuple, since the
+blanket impl for Ord types already covers tuples with the lexicographic order.
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ProductLattice<T>(pub T);
+
+fn product_cmp(a: Option<Ordering>, b: Option<Ordering>) -> Option<Ordering> {
+    match (a?, b?) {
+        (Ordering::Equal, o) | (o, Ordering::Equal) => Some(o),
+        (x, y) if x == y => Some(x),
+        _ => None,
     }
 }
 
-impl PartialOrd for FreeL32 {
+macro_rules! impl_product_lattice {
+    ($(($($t:ident . $i:tt),+)),*) => {
+        $(
+            impl<$($t: Lattice),+> PartialOrd for ProductLattice<($($t,)+)> {
+                fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                    let ord = Some(Ordering::Equal);
+                    $(let ord = product_cmp(ord, self.0 .$i.partial_cmp(&other.0 .$i));)+
+                    ord
+                }
+            }
+
+            impl<$($t: Lattice),+> Lattice for ProductLattice<($($t,)+)> {
+                fn join(self, other: Self) -> Self {
+                    ProductLattice(($(self.0 .$i.join(other.0 .$i),)+))
+                }
+                fn meet(self, other: Self) -> Self {
+                    ProductLattice(($(self.0 .$i.meet(other.0 .$i),)+))
+                }
+            }
+
+            impl<$($t: BoundedLattice),+> BoundedLattice for ProductLattice<($($t,)+)> {
+                const TOP: Self = ProductLattice(($($t::TOP,)+));
+                const BOT: Self = ProductLattice(($($t::BOT,)+));
+            }
+        )*
+    };
+}
+
+impl_product_lattice!((A.0, B.1), (A.0, B.1, C.2), (A.0, B.1, C.2, D.3));
+
+/*
+A lattice turned upside down: the order is reversed, join and meet swap, and so
+do TOP and BOT. Queries over Dual<T> are the mirror images of those over T.
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Dual<T>(pub T);
+
+impl<T: Lattice> PartialOrd for Dual<T> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        let i1 = self.val & !other.val;
-        let i2 = !self.val & other.val;
-        match (i1, i2) {
-            (0, 0) => Some(Ordering::Equal),
-            (0, _) => Some(Ordering::Less),
-            (_, 0) => Some(Ordering::Greater),
-            _ => None,
-        }
+        other.0.partial_cmp(&self.0)
     }
 }
 
-impl Lattice for FreeL32 {
+impl<T: Lattice> Lattice for Dual<T> {
+    fn join(self, other: Self) -> Self {
+        Dual(self.0.meet(other.0))
+    }
     fn meet(self, other: Self) -> Self {
-        Self {
-            val: self.val & other.val,
+        Dual(self.0.join(other.0))
+    }
+}
+
+impl<T: BoundedLattice> BoundedLattice for Dual<T> {
+    const TOP: Self = Dual(T::BOT);
+    const BOT: Self = Dual(T::TOP);
+}
+
+/*
+The flat lattice over any Eq type: its values are pairwise incomparable, with Bot
+below and Top above all of them. Joining or meeting two distinct values gives Top
+or Bot, so a block's range is a single value if the block is uniform and
+[Bot, Top] otherwise, which suits categorical data.
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Flat<T> {
+    Bot,
+    Value(T),
+    Top,
+}
+
+impl<T: Eq> PartialOrd for Flat<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Flat::Value(a), Flat::Value(b)) => (a == b).then_some(Ordering::Equal),
+            (Flat::Bot, Flat::Bot) | (Flat::Top, Flat::Top) => Some(Ordering::Equal),
+            (Flat::Bot, _) | (_, Flat::Top) => Some(Ordering::Less),
+            (_, Flat::Bot) | (Flat::Top, _) => Some(Ordering::Greater),
         }
     }
+}
+
+impl<T: Eq> Lattice for Flat<T> {
     fn join(self, other: Self) -> Self {
-        Self {
-            val: self.val | other.val,
+        match (self, other) {
+            (Flat::Bot, x) | (x, Flat::Bot) => x,
+            (Flat::Value(a), Flat::Value(b)) if a == b => Flat::Value(a),
+            _ => Flat::Top,
         }
     }
+    fn meet(self, other: Self) -> Self {
+        match (self, other) {
+            (Flat::Top, x) | (x, Flat::Top) => x,
+            (Flat::Value(a), Flat::Value(b)) if a == b => Flat::Value(a),
+            _ => Flat::Bot,
+        }
+    }
+}
+
+impl<T: Eq> BoundedLattice for Flat<T> {
+    const TOP: Self = Flat::Top;
+    const BOT: Self = Flat::Bot;
+}
+
+/*
+Finite sets of any Ord type ordered by inclusion, for generator sets wider than
+the FreeL* words. There is no TOP without a known universe, so this is not a
+BoundedLattice.
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct SetLattice<T: Ord + Clone>(pub BTreeSet<T>);
+
+impl<T: Ord + Clone> PartialOrd for SetLattice<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self.0.is_subset(&other.0), other.0.is_subset(&self.0)) {
+            (true, true) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Less),
+            (false, true) => Some(Ordering::Greater),
+            (false, false) => None,
+        }
+    }
+}
+
+impl<T: Ord + Clone> Lattice for SetLattice<T> {
+    fn join(self, other: Self) -> Self {
+        let (mut big, small) = if self.0.len() >= other.0.len() {
+            (self.0, other.0)
+        } else {
+            (other.0, self.0)
+        };
+        big.extend(small);
+        SetLattice(big)
+    }
+    fn meet(self, other: Self) -> Self {
+        let (big, mut small) = if self.0.len() >= other.0.len() {
+            (self.0, other.0)
+        } else {
+            (other.0, self.0)
+        };
+        small.retain(|x| big.contains(x));
+        SetLattice(small)
+    }
+}
+
+impl<T: Ord + Clone> FromIterator<T> for SetLattice<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        SetLattice(iter.into_iter().collect())
+    }
+}
+
+/*
+A lattice key with a payload carried along. Comparisons, join and meet only look
+at the key; join and meet keep the payload of self, so the payloads in block
+ranges are arbitrary and only the ones in the table are meaningful.
+*/
+#[derive(Debug, Clone)]
+pub struct Keyed<K, P> {
+    pub key: K,
+    pub payload: P,
+}
+
+impl<K, P> Keyed<K, P> {
+    pub fn new(key: K, payload: P) -> Self {
+        Keyed { key, payload }
+    }
+}
+
+impl<K: PartialEq, P> PartialEq for Keyed<K, P> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
 }
 
-impl BoundedLattice for FreeL32 {
-    const TOP: Self = Self { val: !0 };
+impl<K: PartialOrd, P> PartialOrd for Keyed<K, P> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.key.partial_cmp(&other.key)
+    }
+}
+
+impl<K: Lattice, P> Lattice for Keyed<K, P> {
+    fn join(self, other: Self) -> Self {
+        Keyed::new(self.key.join(other.key), self.payload)
+    }
+    fn meet(self, other: Self) -> Self {
+        Keyed::new(self.key.meet(other.key), self.payload)
+    }
+}
+
+/*
+A counter that saturates at CAP. Values are clamped on construction, so the
+total order lattice (join = max, meet = min) stays within 0..=CAP.
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SaturatingCounter<const CAP: u32> {
+    val: u32,
+}
+
+impl<const CAP: u32> SaturatingCounter<CAP> {
+    pub fn new(val: u32) -> Self {
+        Self { val: min(val, CAP) }
+    }
+
+    pub fn val(&self) -> u32 {
+        self.val
+    }
+
+    pub fn saturating_add(self, n: u32) -> Self {
+        Self::new(self.val.saturating_add(n))
+    }
+
+    pub fn is_saturated(&self) -> bool {
+        self.val == CAP
+    }
+}
+
+impl<const CAP: u32> BoundedLattice for SaturatingCounter<CAP> {
+    const TOP: Self = Self { val: CAP };
     const BOT: Self = Self { val: 0 };
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub struct FreeL64 {
-    pub val: u64,
+/*
+A path cost in the min-plus (tropical) semiring. As a lattice it is the total
+order, so block ranges bound the costs by their min and max, while combine is
+the semiring product: the cost of two paths in sequence. Costs saturate at
+INFINITY, which stands for an unreachable path.
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TropicalCost {
+    val: u64,
+}
+
+impl TropicalCost {
+    pub const INFINITY: Self = Self { val: u64::MAX };
+
+    pub fn new(val: u64) -> Self {
+        Self { val }
+    }
+
+    pub fn val(&self) -> u64 {
+        self.val
+    }
+
+    pub fn combine(self, other: Self) -> Self {
+        Self::new(self.val.saturating_add(other.val))
+    }
+
+    pub fn is_infinite(&self) -> bool {
+        *self == Self::INFINITY
+    }
 }
 
-impl FreeL64 {
-    pub fn new(i: u64) -> Self {
-        Self { val: i }
+impl BoundedLattice for TropicalCost {
+    const TOP: Self = Self::INFINITY;
+    const BOT: Self = Self { val: 0 };
+}
+
+/*
+Lattices of sets ordered by inclusion, like the bitsets below.
+*/
+pub trait FreeLattice: BoundedLattice + Copy {
+    fn popcount(&self) -> u32;
+
+    /*
+    The number of generators below self, its distance from BOT.
+    */
+    fn rank(&self) -> u32 {
+        self.popcount()
+    }
+}
+
+/*
+Compares two sets given whether each has elements the other lacks.
+*/
+fn subset_cmp(self_extra: bool, other_extra: bool) -> Option<Ordering> {
+    match (self_extra, other_extra) {
+        (false, false) => Some(Ordering::Equal),
+        (false, true) => Some(Ordering::Less),
+        (true, false) => Some(Ordering::Greater),
+        (true, true) => None,
+    }
+}
+
+/*
+The free distributive lattice on 64 * WORDS generators, i.e. sets of generators
+stored as bit arrays. FreeL32, FreeL64 and FreeL128 are the single-integer versions
+of it, and convert into the matching width.
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct FreeBitset<const WORDS: usize> {
+    pub words: [u64; WORDS],
+}
+
+impl<const WORDS: usize> FreeBitset<WORDS> {
+    pub fn new(words: [u64; WORDS]) -> Self {
+        Self { words }
+    }
+
+    pub fn canonical(&self) -> [u64; WORDS] {
+        self.words
     }
 
     pub fn generator(i: usize) -> Self {
-        Self { val: 1 << i }
+        let mut words = [0; WORDS];
+        words[i / 64] = 1 << (i % 64);
+        Self { words }
     }
 
     pub fn complement(&self) -> Self {
-        Self { val: !self.val }
+        Self {
+            words: self.words.map(|w| !w),
+        }
+    }
+
+    fn zip(self, other: Self, f: impl Fn(u64, u64) -> u64) -> Self {
+        let mut words = self.words;
+        for (w, o) in words.iter_mut().zip(other.words) {
+            *w = f(*w, o);
+        }
+        Self { words }
     }
 }
 
-impl PartialOrd for FreeL64 {
+impl<const WORDS: usize> PartialOrd for FreeBitset<WORDS> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        let i1 = self.val & !other.val;
-        let i2 = !self.val & other.val;
-        match (i1, i2) {
-            (0, 0) => Some(Ordering::Equal),
-            (0, _) => Some(Ordering::Less),
-            (_, 0) => Some(Ordering::Greater),
-            _ => None,
-        }
+        let pairs = || self.words.iter().zip(&other.words);
+        subset_cmp(
+            pairs().any(|(a, b)| a & !b != 0),
+            pairs().any(|(a, b)| !a & b != 0),
+        )
     }
 }
 
-impl Lattice for FreeL64 {
+impl<const WORDS: usize> Lattice for FreeBitset<WORDS> {
     fn meet(self, other: Self) -> Self {
-        Self {
-            val: self.val & other.val,
-        }
+        self.zip(other, |a, b| a & b)
     }
     fn join(self, other: Self) -> Self {
-        Self {
-            val: self.val | other.val,
-        }
+        self.zip(other, |a, b| a | b)
     }
 }
 
-impl BoundedLattice for FreeL64 {
-    const TOP: Self = Self { val: !0 };
-    const BOT: Self = Self { val: 0 };
+impl<const WORDS: usize> BoundedLattice for FreeBitset<WORDS> {
+    const TOP: Self = Self { words: [!0; WORDS] };
+    const BOT: Self = Self { words: [0; WORDS] };
+}
+
+impl<const WORDS: usize> FreeLattice for FreeBitset<WORDS> {
+    fn popcount(&self) -> u32 {
+        self.words.iter().map(|w| w.count_ones()).sum()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct FreeL32 {
+    pub val: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct FreeL64 {
+    pub val: u64,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct FreeL128 {
+    pub val: u128,
+}
+
+macro_rules! impl_free_word {
+    ($($t:ident($w:ty)),*) => {
+        $(
+            impl $t {
+                pub fn new(i: $w) -> Self {
+                    Self { val: i }
+                }
+
+                /*
+                The bit pattern, which determines the element: two values are equal,
+                and hash the same, exactly when their canonical forms are equal.
+                */
+                pub fn canonical(&self) -> $w {
+                    self.val
+                }
+
+                pub fn generator(i: usize) -> Self {
+                    Self { val: 1 << i }
+                }
+
+                pub fn complement(&self) -> Self {
+                    Self { val: !self.val }
+                }
+            }
+
+            impl PartialOrd for $t {
+                fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                    subset_cmp(self.val & !other.val != 0, !self.val & other.val != 0)
+                }
+            }
+
+            impl Lattice for $t {
+                fn meet(self, other: Self) -> Self {
+                    Self {
+                        val: self.val & other.val,
+                    }
+                }
+                fn join(self, other: Self) -> Self {
+                    Self {
+                        val: self.val | other.val,
+                    }
+                }
+            }
+
+            impl BoundedLattice for $t {
+                const TOP: Self = Self { val: !0 };
+                const BOT: Self = Self { val: 0 };
+            }
+
+            impl FreeLattice for $t {
+                fn popcount(&self) -> u32 {
+                    self.val.count_ones()
+                }
+            }
+        )*
+    };
+}
+
+impl_free_word!(FreeL32(u32), FreeL64(u64), FreeL128(u128));
+
+impl From<FreeL32> for FreeBitset<1> {
+    fn from(x: FreeL32) -> Self {
+        Self::new([x.val as u64])
+    }
+}
+
+impl From<FreeL64> for FreeBitset<1> {
+    fn from(x: FreeL64) -> Self {
+        Self::new([x.val])
+    }
+}
+
+impl From<FreeL128> for FreeBitset<2> {
+    fn from(x: FreeL128) -> Self {
+        Self::new([x.val as u64, (x.val >> 64) as u64])
+    }
+}
+
+/// A set of alphanumeric characters, one bit each, plus a bucket for other ASCII
+/// characters and one for everything else.
+///
+/// ```
+/// use latticequeries::lattices::{AlphaNumSet, Lattice};
+///
+/// let set = AlphaNumSet::new("abc123");
+/// assert!(AlphaNumSet::singleton('b') <= set);
+/// assert_eq!(AlphaNumSet::singleton('z').partial_cmp(&set), None);
+/// assert_eq!(set.join(AlphaNumSet::new("z")).to_string(), "123abcz");
+/// assert_eq!(set.complement().meet(set).val.val, 0);
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Hash)]
 pub struct AlphaNumSet {
     pub val: FreeL64,
 }
 
 fn alphanum_to_u8offset(ch: char) -> u8 {
     match ch {
-        '0'..='9' => ch as u8 - '0' as u8,
-        'A'..='Z' => ch as u8 - 'A' as u8 + 10, // 10 to 35
-        'a'..='z' => ch as u8 - 'a' as u8 + 36, // 36 to 61
+        '0'..='9' => ch as u8 - b'0',
+        'A'..='Z' => ch as u8 - b'A' + 10, // 10 to 35
+        'a'..='z' => ch as u8 - b'a' + 36, // 36 to 61
         _ => {
             if ch.is_ascii() {
                 62
@@ -201,9 +688,9 @@ fn alphanum_to_u8offset(ch: char) -> u8 {
 
 fn u8offset_to_alphanum(n: u8) -> char {
     match n {
-        0..=9 => (n + '0' as u8) as char,
-        10..=35 => (n - 10 + 'A' as u8) as char,
-        36..=61 => (n - 36 + 'a' as u8) as char,
+        0..=9 => (n + b'0') as char,
+        10..=35 => (n - 10 + b'A') as char,
+        36..=61 => (n - 36 + b'a') as char,
         62 => ':',
         _ => '?',
     }
@@ -231,6 +718,45 @@ impl AlphaNumSet {
             val: self.val.complement(),
         }
     }
+
+    /*
+    Whether ch's bucket is in the set, so any ASCII punctuation matches any other.
+    */
+    pub fn contains(&self, ch: char) -> bool {
+        self.val.val & (1 << alphanum_to_u8offset(ch)) != 0
+    }
+
+    pub fn difference(self, other: Self) -> Self {
+        self.meet(other.complement())
+    }
+
+    pub fn symmetric_difference(self, other: Self) -> Self {
+        self.difference(other).join(other.difference(self))
+    }
+
+    pub fn len(&self) -> u32 {
+        self.popcount()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.val.val == 0
+    }
+
+    /*
+    The characters in bit order: digits, upper case, lower case, then ':' and '?'
+    standing in for the two fallback buckets, as in Display.
+    */
+    pub fn iter(&self) -> impl Iterator<Item = char> {
+        // Visits only the set bits, lowest first.
+        let mut bits = self.val.val;
+        std::iter::from_fn(move || {
+            let i = bits.trailing_zeros();
+            (bits != 0).then(|| {
+                bits &= bits - 1;
+                u8offset_to_alphanum(i as u8)
+            })
+        })
+    }
 }
 
 impl Lattice for AlphaNumSet {
@@ -251,14 +777,81 @@ impl BoundedLattice for AlphaNumSet {
     const BOT: Self = AlphaNumSet { val: FreeL64::BOT };
 }
 
+impl FreeLattice for AlphaNumSet {
+    fn popcount(&self) -> u32 {
+        self.val.popcount()
+    }
+}
+
+impl FromIterator<char> for AlphaNumSet {
+    fn from_iter<I: IntoIterator<Item = char>>(iter: I) -> Self {
+        iter.into_iter().fold(AlphaNumSet::BOT, |acc, ch| {
+            acc.join(AlphaNumSet::singleton(ch))
+        })
+    }
+}
+
+/*
+Same as new, so parsing never fails.
+*/
+impl std::str::FromStr for AlphaNumSet {
+    type Err = std::convert::Infallible;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(AlphaNumSet::new(s))
+    }
+}
+
 impl std::fmt::Display for AlphaNumSet {
     fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
-        let v: u64 = self.val.val;
-        for i in 0..=63 {
-            if v & (1 << i) != 0 {
-                f.write_char(u8offset_to_alphanum(i));
-            }
+        for ch in self.iter() {
+            f.write_char(ch)?;
         }
         Ok(())
     }
 }
+
+/*
+Serialized as its characters, like Display, except that the non-ASCII bucket is
+written as U+FFFD rather than '?', which would read back as ASCII punctuation.
+*/
+#[cfg(feature = "serde")]
+impl serde::Serialize for AlphaNumSet {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let s: String = (0..=63)
+            .filter(|i| self.val.val & (1 << i) != 0)
+            .map(|i| match i {
+                63 => '\u{FFFD}',
+                _ => u8offset_to_alphanum(i),
+            })
+            .collect();
+        serializer.serialize_str(&s)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for AlphaNumSet {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        Ok(AlphaNumSet::new(&s))
+    }
+}
+
+/*
+A timestamp together with a tag set. A block summary bounds the times by their
+min and max and the tags by their intersection and union.
+*/
+pub type TimeTagged = ProductLattice<(i64, FreeL32)>;
+
+impl TimeTagged {
+    pub fn new(time: i64, tags: FreeL32) -> Self {
+        ProductLattice((time, tags))
+    }
+
+    pub fn time(&self) -> i64 {
+        self.0 .0
+    }
+
+    pub fn tags(&self) -> FreeL32 {
+        self.0 .1
+    }
+}
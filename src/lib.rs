@@ -1,12 +1,80 @@
+pub mod hicache;
+pub mod hidisk;
 pub mod hiqueries;
+pub mod hitable;
 pub mod hivecs;
+#[cfg(feature = "instrumented")]
+pub mod instrumented;
 pub mod lattices;
+pub mod reachability;
+pub mod tagged;
 
 #[cfg(test)]
 mod tests {
-    use crate::hiqueries::HiQuery;
-    use crate::hivecs::HiVec;
-    use crate::lattices::{AlphaNumSet, BoundedLattice, FreeL32, Lattice};
+    use crate::hicache::QueryCache;
+    use crate::hidisk::DiskBackedHiVec;
+    use crate::hiqueries::{
+        co_occurrence, merge_streams, BoxedQuery, CausalQuery, CoOccurStats, ConstQuery, HiQuery,
+        IndexSetQuery, IntersectionQuery, Membership, NegatableQuery, NotQuery, RemapQuery,
+        TraversalStrategy, UnionQuery,
+    };
+    use crate::hitable::{HiTable, Predicate};
+    use crate::hivecs::{same_data, same_queries, HiVec, RunningAny};
+    use crate::lattices::{
+        AlphaNumSet, BoundedLattice, Dual, Flat, FreeBitset, FreeL128, FreeL32, FreeLattice, Keyed,
+        Lattice, LatticeRange, SaturatingCounter, SetLattice, Singleton, TimeTagged, TropicalCost,
+    };
+    use crate::reachability::ReachabilityBuilder;
+    use crate::tagged::TaggedHiVec;
+    use std::cell::Cell;
+    use std::sync::Arc;
+
+    // Counts how many times a traversal probes the wrapped query.
+    struct Probed<'a, Q> {
+        q: &'a Q,
+        probes: Cell<usize>,
+        threshold: Option<usize>,
+    }
+
+    impl<'a, Q> Probed<'a, Q> {
+        fn new(q: &'a Q) -> Self {
+            Probed {
+                q,
+                probes: Cell::new(0),
+                threshold: None,
+            }
+        }
+        fn with_threshold(q: &'a Q, threshold: usize) -> Self {
+            Probed {
+                threshold: Some(threshold),
+                ..Probed::new(q)
+            }
+        }
+        fn take(&self) -> usize {
+            self.probes.replace(0)
+        }
+    }
+
+    impl<'a, Q: HiQuery<N, F>, const N: usize, const F: usize> HiQuery<N, F> for Probed<'a, Q> {
+        fn length(&self) -> usize {
+            self.q.length()
+        }
+        fn query_at(&self, i: usize) -> bool {
+            self.probes.set(self.probes.get() + 1);
+            self.q.query_at(i)
+        }
+        fn hiquery(&self, layer: usize, i: usize) -> bool {
+            self.probes.set(self.probes.get() + 1);
+            self.q.hiquery(layer, i)
+        }
+        fn hiquery_all(&self, layer: usize, i: usize) -> bool {
+            self.probes.set(self.probes.get() + 1);
+            self.q.hiquery_all(layer, i)
+        }
+        fn linear_scan_threshold(&self) -> usize {
+            self.threshold.unwrap_or(self.q.linear_scan_threshold())
+        }
+    }
 
     #[test]
     fn test_constructors_accessors() {
@@ -52,6 +120,38 @@ mod tests {
         assert_eq!(q1.count() + q2.count(), hv.len());
     }
 
+    #[test]
+    fn test_locationqueries_backward() {
+        let v = vec![true, false, false, true, true, false, false, false, true];
+        let hv: HiVec<_, 3, 2> = HiVec::new(v.clone());
+        let q1 = hv.query_equals(true);
+        let q2 = hv.query_equals(false);
+        assert_eq!(q1.findprev(100), Some(8));
+        assert_eq!(q1.findprev(8), Some(8));
+        assert_eq!(q1.findprev(7), Some(4));
+        assert_eq!(q1.findprev(4), Some(4));
+        assert_eq!(q1.findprev(3), Some(3));
+        assert_eq!(q1.findprev(2), Some(0));
+        assert_eq!(q1.findprev(0), Some(0));
+        assert_eq!(q2.findprev(8), Some(7));
+        assert_eq!(q2.findprev(5), Some(5));
+        assert_eq!(q2.findprev(4), Some(2));
+        assert_eq!(q2.findprev(1), Some(1));
+        assert_eq!(q2.findprev(0), None);
+
+        // Sparse matches, so that the scan climbs over whole blocks ending at i.
+        let v: Vec<bool> = (0..300)
+            .map(|i| i == 5 || i == 130 || i == 131 || i == 299)
+            .collect();
+        let hv: HiVec<_, 3, 2> = HiVec::new(v.clone());
+        let wide: HiVec<_, 2, 5> = HiVec::new(v.clone());
+        for i in (0..300).rev() {
+            let naive = (0..=i).rev().find(|&j| v[j]);
+            assert_eq!(hv.query_equals(true).findprev(i), naive);
+            assert_eq!(wide.query_equals(true).findprev(i), naive);
+        }
+    }
+
     #[test]
     fn test_lattice() {
         println!("Test group 1");
@@ -69,4 +169,2444 @@ mod tests {
         let a3 = AlphaNumSet::new("o").complement();
         assert_eq!(a1.join(a2).meet(a3).to_string(), "LZadehlrwz:");
     }
+
+    #[test]
+    fn test_lattice_128() {
+        let l1 = FreeL128::new(1 << 100 | 1 << 70 | 0b0111);
+        let l2 = FreeL128::new(1 << 100 | 1 << 64 | 0b0100);
+        assert_eq!(l1.join(l2).val, 1 << 100 | 1 << 70 | 1 << 64 | 0b0111);
+        assert_eq!(l1.meet(l2).val, 1 << 100 | 0b0100);
+        assert_eq!(l1.meet(BoundedLattice::TOP), l1);
+        assert_eq!(l1.join(BoundedLattice::BOT), l1);
+        assert_eq!(l1.join(BoundedLattice::TOP), BoundedLattice::TOP);
+        assert_eq!(l1.meet(BoundedLattice::BOT), BoundedLattice::BOT);
+        assert_eq!(l1.partial_cmp(&l2), None);
+        assert!(FreeL128::generator(100) < l1);
+        assert_eq!(FreeL128::generator(101).partial_cmp(&l1), None);
+        assert_eq!(l1.complement().meet(l1), FreeL128::BOT);
+        assert_eq!(l1.popcount(), 5);
+    }
+
+    #[test]
+    fn test_hitable() {
+        let flags = vec![true, false, false, true, true, false, false, false, true];
+        let nums: Vec<u32> = vec![3, 3, 1, 3, 2, 3, 3, 1, 3];
+        let mut table: HiTable<3, 2> = HiTable::new();
+        let c0 = table.add_column(HiVec::new(flags.clone()));
+        let c1 = table.add_column(HiVec::new(nums.clone()));
+        assert_eq!(table.num_columns(), 2);
+        assert_eq!(table.len(), 9);
+        let rows =
+            table.and_across(&[(c0, Predicate::equals(true)), (c1, Predicate::equals(3u32))]);
+        let expected: Vec<usize> = (0..9).filter(|&i| flags[i] && nums[i] == 3).collect();
+        assert_eq!(rows, expected);
+        assert_eq!(rows, vec![0, 3, 8]);
+        assert!(table.query(c1).query_at(&Predicate::equals(1u32), 2));
+        assert_eq!(table.and_across(&[]).len(), 9);
+    }
+
+    #[test]
+    fn test_saturating_counter() {
+        type C = SaturatingCounter<10>;
+        assert_eq!(C::new(10).val(), 10);
+        assert_eq!(C::new(12), C::TOP);
+        assert_eq!(C::new(7).saturating_add(5), C::TOP);
+        assert!(C::new(u32::MAX).is_saturated());
+        assert_eq!(C::new(3).join(C::new(8)), C::new(8));
+        assert_eq!(C::new(3).meet(C::new(8)), C::new(3));
+
+        let raw: Vec<u32> = vec![0, 1, 2, 3, 12, 10, 4, 5];
+        let hv: HiVec<C, 3, 2> = HiVec::new(raw.iter().map(|&x| C::new(x)).collect());
+        assert_eq!(hv.query_geq(10).iter().collect::<Vec<_>>(), vec![4, 5]);
+        assert_eq!(hv.query_geq(25).iter().collect::<Vec<_>>(), vec![4, 5]);
+        assert_eq!(hv.saturated().count(), 2);
+        for t in 0..=10 {
+            let naive: Vec<usize> = (0..raw.len()).filter(|&i| raw[i].min(10) >= t).collect();
+            assert_eq!(hv.query_geq(t).iter().collect::<Vec<_>>(), naive);
+        }
+        // Block maxima are 1, 3, 12->10, 5: only the third first-layer block can hold a 10.
+        let q = hv.query_geq(10);
+        assert!(!q.hiquery(1, 0));
+        assert!(!q.hiquery(1, 1));
+        assert!(q.hiquery(1, 2));
+        assert!(!q.hiquery(1, 3));
+        assert!(q.hiquery(2, 1));
+        assert!(!q.hiquery(2, 0));
+    }
+
+    #[test]
+    fn test_reshape_same_data() {
+        let v: Vec<u32> = vec![4, 1, 1, 3, 4, 2, 2, 2, 0, 1, 4];
+        let hv: HiVec<_, 3, 2> = HiVec::new(v.clone());
+        let reshaped: HiVec<_, 2, 4> = hv.clone().reshape();
+        assert_eq!(reshaped.as_table(), &v[..]);
+        assert!(same_data(&hv, &reshaped));
+        assert!(same_queries(&hv, &reshaped, &[0, 1, 2, 3, 4, 5]));
+        let other: HiVec<_, 2, 4> = HiVec::new(vec![4, 1, 1, 3, 4, 2, 2, 2, 0, 1, 3]);
+        assert!(!same_data(&hv, &other));
+        assert!(!same_queries(&hv, &other, &[3, 4]));
+        assert!(same_queries(&hv, &other, &[0, 1, 2]));
+    }
+
+    #[test]
+    fn test_traversal_strategies() {
+        let v = vec![true, false, false, true, true, false, false, false, true];
+        let hv: HiVec<_, 3, 2> = HiVec::new(v);
+        for item in [true, false] {
+            let q = hv.query_equals(item);
+            let expected: Vec<usize> = q.iter().collect();
+            assert_eq!(q.collect_with(TraversalStrategy::BottomUpSkip), expected);
+            assert_eq!(q.collect_with(TraversalStrategy::TopDownRecurse), expected);
+        }
+
+        let sparse: HiVec<_, 4, 4> = HiVec::new((0..256).map(|i| i == 37 || i == 200).collect());
+        let dense: HiVec<_, 4, 4> = HiVec::new((0..256).map(|i| i % 7 != 0).collect());
+        let mut probes = vec![];
+        for hv in [&sparse, &dense] {
+            let q = hv.query_equals(true);
+            let p = Probed::new(&q);
+            let bottom_up = p.collect_with(TraversalStrategy::BottomUpSkip);
+            let bottom_up_probes = p.take();
+            let top_down = p.collect_with(TraversalStrategy::TopDownRecurse);
+            let top_down_probes = p.take();
+            assert_eq!(bottom_up, top_down);
+            assert_eq!(bottom_up, q.iter().collect::<Vec<_>>());
+            probes.push((bottom_up_probes, top_down_probes));
+        }
+        println!(
+            "Probes (bottom-up, top-down): sparse {:?}, dense {:?}",
+            probes[0], probes[1]
+        );
+        // Both skip the empty regions of the sparse column.
+        assert!(probes[0].0 < 64 && probes[0].1 < 64);
+        // Dense data gets no pruning, so descending costs extra block probes.
+        assert!(probes[1].0 < probes[1].1);
+    }
+
+    #[test]
+    fn test_tagged_hivec() {
+        struct Flags;
+        let v = vec![true, false, false, true, true, false, false, false, true];
+        let hv: TaggedHiVec<Flags, _, 3, 2> = TaggedHiVec::new(v);
+        let q = hv.query_equals(true);
+        let rows: Vec<_> = q.iter().collect();
+        assert_eq!(
+            rows.iter().map(|r| r.index()).collect::<Vec<_>>(),
+            vec![0, 3, 4, 8]
+        );
+        assert_eq!(
+            rows.iter().map(|&r| usize::from(r)).collect::<Vec<_>>(),
+            vec![0, 3, 4, 8]
+        );
+        assert!(rows.iter().all(|&r| hv.get(r) == Some(&true)));
+        assert_eq!(q.findnext(rows[1]), Some(rows[1]));
+        assert_eq!(q.findnext(hv.row(5).unwrap()), Some(rows[3]));
+        assert_eq!(hv.row(9), None);
+        assert_eq!(q.inner().count(), 4);
+    }
+
+    #[test]
+    fn test_head_tail() {
+        let v = vec![true, false, false, true, true, false, false, false, true];
+        let hv: HiVec<_, 3, 2> = HiVec::new(v);
+        let q1 = hv.query_equals(true);
+        assert_eq!(q1.findlast(), Some(8));
+        assert_eq!(q1.findprev(7), Some(4));
+        assert_eq!(q1.findprev(2), Some(0));
+        assert_eq!(hv.query_equals(false).findprev(0), None);
+        assert_eq!(q1.head_tail(2), (vec![0, 3], vec![4, 8]));
+        assert_eq!(q1.head_tail(3), (vec![0, 3, 4], vec![8]));
+        assert_eq!(q1.head_tail(5), (vec![0, 3, 4, 8], vec![]));
+        assert_eq!(q1.head_tail(0), (vec![], vec![]));
+        let q2 = hv.query_equals(false);
+        assert_eq!(q2.head_tail(2), (vec![1, 2], vec![6, 7]));
+
+        let w: Vec<bool> = (0..77).map(|i| i % 13 == 5 || i == 40).collect();
+        let hw: HiVec<_, 3, 3> = HiVec::new(w.clone());
+        let q = hw.query_equals(true);
+        for i in 0..80 {
+            let naive = (0..=i.min(76)).rev().find(|&j| w[j]);
+            assert_eq!(q.findprev(i), naive);
+        }
+    }
+
+    #[test]
+    fn test_split_ranges() {
+        let r = LatticeRange::new(10u32, 3);
+        let (lo, hi) = r.split().unwrap();
+        assert_eq!(
+            (lo, hi),
+            (LatticeRange::new(6, 3), LatticeRange::new(10, 7))
+        );
+        assert_eq!(LatticeRange::singleton(4u32).split(), None);
+        let (lo, hi) = LatticeRange::new(i64::MAX, i64::MIN).split().unwrap();
+        assert_eq!((*lo.top(), *hi.bottom()), (-1, 0));
+
+        // Splitting down to singletons covers every value exactly once.
+        let mut pending = vec![LatticeRange::new(20i32, -7)];
+        let mut covered = vec![];
+        while let Some(r) = pending.pop() {
+            match r.split() {
+                Some((lo, hi)) => {
+                    assert!(lo.top() < hi.bottom());
+                    pending.push(hi);
+                    pending.push(lo);
+                }
+                None => covered.push(*r.bottom()),
+            }
+        }
+        assert_eq!(covered, (-7..=20).collect::<Vec<_>>());
+
+        let v: Vec<u32> = vec![9, 4, 17, 4, 30, 12, 5, 9, 0, 22, 11];
+        let hv: HiVec<_, 3, 2> = HiVec::new(v.clone());
+        let mut naive: Vec<usize> = (0..v.len()).filter(|&i| (4..=17).contains(&v[i])).collect();
+        naive.sort_by_key(|&i| (v[i], i));
+        assert_eq!(hv.query_between(LatticeRange::new(17, 4)), naive);
+        assert_eq!(
+            hv.query_between(LatticeRange::new(3, 1)),
+            Vec::<usize>::new()
+        );
+    }
+
+    #[test]
+    fn test_rle_selection() {
+        let v: Vec<bool> = (0..100)
+            .map(|i| (20..23).contains(&i) || i == 57 || i >= 90)
+            .collect();
+        let hv: HiVec<_, 4, 3> = HiVec::new(v.clone());
+        let q = hv.query_equals(true);
+        assert_eq!(q.mask(), v);
+        for group_size in [1, 4, 10, 16, 100, 128] {
+            let runs = q.to_rle_selection(group_size);
+            let mut expanded = vec![];
+            let mut pos = 0;
+            for &(selected, len) in &runs {
+                assert!(len > 0);
+                let within_group = pos / group_size == (pos + len - 1) / group_size;
+                let whole_groups = pos % group_size == 0 && len % group_size == 0;
+                assert!(within_group || whole_groups);
+                expanded.extend(std::iter::repeat_n(selected, len));
+                pos += len;
+            }
+            assert_eq!(expanded, v);
+        }
+        assert_eq!(
+            q.to_rle_selection(10),
+            vec![
+                (false, 20),
+                (true, 3),
+                (false, 7),
+                (false, 20),
+                (false, 7),
+                (true, 1),
+                (false, 2),
+                (false, 30),
+                (true, 10)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_linear_scan_threshold() {
+        let tiny: HiVec<_, 3, 2> = HiVec::new(vec![false, false, true]);
+        let q = tiny.query_equals(true);
+        assert_eq!(q.linear_scan_threshold(), 4);
+        let linear = Probed::new(&q);
+        let climbing = Probed::with_threshold(&q, 0);
+        assert_eq!(linear.count(), climbing.count());
+        assert_eq!(linear.findprev(1), climbing.findprev(1));
+        let (linear_probes, climbing_probes) = (linear.take(), climbing.take());
+        println!("Probes on a tiny input: linear {linear_probes}, climbing {climbing_probes}");
+        assert!(linear_probes < climbing_probes);
+
+        // Both paths agree wherever the cutoff falls.
+        let v: Vec<bool> = (0..40).map(|i| i % 9 == 4).collect();
+        let hv: HiVec<_, 3, 2> = HiVec::new(v.clone());
+        let q = hv.query_equals(true);
+        for threshold in [0, 3, 8, 100] {
+            let p = Probed::with_threshold(&q, threshold);
+            for i in 0..=40 {
+                assert_eq!(p.findnext(i), (i..40).find(|&j| v[j]));
+                assert_eq!(p.findprev(i), (0..=i.min(39)).rev().find(|&j| v[j]));
+            }
+        }
+    }
+
+    #[test]
+    fn test_running_any() {
+        let mut ra: RunningAny<3, 2> = RunningAny::new(vec![]);
+        assert!(!ra.any());
+        let values = [
+            false, false, false, true, false, false, false, false, true, false,
+        ];
+        for (n, &x) in values.iter().enumerate() {
+            ra.push(x);
+            assert_eq!(ra.any(), values[..=n].contains(&true));
+            let rebuilt: HiVec<_, 3, 2> = HiVec::new(values[..=n].to_vec());
+            assert!(same_queries(ra.as_hivec(), &rebuilt, &[true, false]));
+        }
+        assert_eq!(ra.count_true(), 2);
+        assert!(!ra.block_any(0, 0));
+        assert!(ra.block_any(0, 1));
+        assert!(ra.block_any(1, 0));
+        assert!(ra.block_any(2, 0));
+        ra.mutate(3, |x| *x = false);
+        assert!(ra.any());
+        assert!(!ra.block_any(0, 1));
+        assert!(!ra.block_any(1, 0));
+        ra.mutate(3, |x| *x = false);
+        assert_eq!(ra.count_true(), 1);
+        assert_eq!(
+            ra.as_hivec().query_equals(true).iter().collect::<Vec<_>>(),
+            vec![8]
+        );
+        ra.mutate(9, |x| *x = true);
+        let rebuilt: HiVec<_, 3, 2> = HiVec::new(ra.as_hivec().as_table().to_vec());
+        assert!(same_queries(ra.as_hivec(), &rebuilt, &[true, false]));
+        assert_eq!(ra.count_true(), 2);
+    }
+
+    #[test]
+    fn test_query_debug() {
+        let v = vec![true, false, false, true, true, false, false, false, true];
+        let hv: HiVec<_, 3, 2> = HiVec::new(v);
+        let q1 = hv.query_equals(true);
+        assert_eq!(format!("{q1:?}"), "EqualsQuery { item: true, length: 9 }");
+        let q2 = hv.query_range(LatticeRange::new(true, false));
+        let and = q1.rc().and(q2.rc());
+        let s = format!("{and:?}");
+        assert!(s.starts_with("AndQuery { length: 9, "));
+        assert!(s.contains(
+            "RangeQuery { range: LatticeRange { top: true, bottom: false }, length: 9 }"
+        ));
+        let or = hv.query_equals(false).rc().or(and.rc());
+        let s = format!("{or:?}");
+        assert!(s.starts_with("OrQuery { length: 9, q1: EqualsQuery { item: false"));
+        assert!(s.contains("AndQuery"));
+        assert!(!s.contains("table"));
+    }
+
+    #[test]
+    fn test_distinct_values() {
+        let v = vec![true, false, false, true, true, false, false, false, true];
+        let hv: HiVec<_, 3, 2> = HiVec::new(v);
+        let mut distinct = hv.distinct_values();
+        distinct.sort();
+        assert_eq!(distinct, vec![false, true]);
+
+        let runs: Vec<u32> = [7; 16]
+            .into_iter()
+            .chain([3, 3, 9])
+            .chain([7; 13])
+            .collect();
+        let hr: HiVec<_, 3, 4> = HiVec::new(runs);
+        assert_eq!(hr.distinct_values(), vec![7, 3, 9]);
+        assert!(HiVec::<u32, 3, 4>::new(vec![]).distinct_values().is_empty());
+    }
+
+    #[test]
+    fn test_masked_popcount() {
+        let sets: Vec<FreeL32> = [
+            0b0001, 0b0011, 0b0001, 0b0000, 0b1111, 0b0111, 0b1000, 0b0110,
+        ]
+        .into_iter()
+        .chain([0b1_0000_0000; 8])
+        .map(FreeL32::new)
+        .collect();
+        let hv: HiVec<_, 3, 2> = HiVec::new(sets.clone());
+        let mask = FreeL32::new(0b0111);
+        for (lo, hi) in [(0, 0), (1, 1), (2, 3), (3, 3), (0, 32)] {
+            let q = hv.query_masked_popcount_between(mask, lo, hi);
+            let naive: Vec<usize> = (0..sets.len())
+                .filter(|&i| (lo..=hi).contains(&(sets[i].val & mask.val).count_ones()))
+                .collect();
+            assert_eq!(q.iter().collect::<Vec<_>>(), naive);
+        }
+        // The first block holds {0}, {0, 1}: masked popcounts lie within [1, 2].
+        let q = hv.query_masked_popcount_between(mask, 3, 3);
+        assert!(!q.hiquery(1, 0));
+        assert!(q.hiquery(1, 2));
+        // The second half is all bit 8, which the mask removes entirely.
+        assert!(!hv.query_masked_popcount_between(mask, 1, 32).hiquery(3, 1));
+    }
+
+    #[test]
+    fn test_block_is_uniform() {
+        let v = vec![true, false, false, true, true, false, false, false, true];
+        let hv: HiVec<_, 3, 2> = HiVec::new(v);
+        let uniform: Vec<bool> = (0..5).map(|b| hv.block_is_uniform(0, b)).collect();
+        assert_eq!(uniform, vec![false, false, false, true, true]);
+        assert!(!hv.block_is_uniform(1, 1));
+        assert!(hv.block_is_uniform(1, 2));
+    }
+
+    #[test]
+    fn test_fingerprint_cache() {
+        let v = vec![true, false, false, true, true, false, false, false, true];
+        let mut hv: HiVec<_, 3, 2> = HiVec::new(v);
+        let mut cache = QueryCache::new();
+        assert_eq!(cache.query_equals(&hv, true), &[0, 3, 4, 8]);
+        assert_eq!(cache.query_equals(&hv, true), &[0, 3, 4, 8]);
+        assert_eq!(cache.misses(), 1);
+
+        let fingerprint = hv.content_fingerprint();
+        hv.mutate(3, |x| *x = true);
+        assert_eq!(hv.content_fingerprint(), fingerprint);
+        assert_eq!(cache.query_equals(&hv, true), &[0, 3, 4, 8]);
+        assert_eq!(cache.misses(), 1);
+
+        // The last top-layer block only holds index 8, so its range changes.
+        hv.mutate(8, |x| *x = false);
+        assert_ne!(hv.content_fingerprint(), fingerprint);
+        assert_eq!(cache.query_equals(&hv, true), &[0, 3, 4]);
+        assert_eq!(cache.misses(), 2);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_borrowed_hivec() {
+        let v: Vec<u32> = vec![5, 2, 2, 7, 5, 5, 1, 0, 2, 9, 5];
+        let owned: HiVec<_, 3, 2> = HiVec::new(v.clone());
+        let borrowed = HiVec::<_, 3, 2>::new_borrowed(&v);
+        assert_eq!(borrowed.len(), owned.len());
+        assert_eq!(borrowed.as_table(), owned.as_table());
+        for x in 0..10 {
+            let a: Vec<usize> = owned.query_equals(x).iter().collect();
+            assert_eq!(borrowed.query_equals(x).iter().collect::<Vec<_>>(), a);
+        }
+        let r = LatticeRange::new(5, 2);
+        assert_eq!(
+            borrowed.query_range(r).iter().collect::<Vec<_>>(),
+            owned.query_range(r).iter().collect::<Vec<_>>()
+        );
+        assert_eq!(borrowed.query_range(r).count(), 7);
+    }
+
+    #[test]
+    fn test_sum_where() {
+        let v: Vec<i64> = vec![5, -2, 2, 7, i64::MAX, 5, 1, 0, i64::MAX, 9, 5];
+        let hv: HiVec<_, 3, 2> = HiVec::new(v.clone());
+        let q = hv.query_range(LatticeRange::new(5, 0));
+        assert_eq!(hv.sum_where(&q), 5 + 2 + 5 + 1 + 5);
+        let big = hv.query_range(LatticeRange::new(i64::MAX, 6));
+        assert_eq!(hv.sum_where(&big), 2 * i64::MAX as i128 + 7 + 9);
+        assert_eq!(hv.sum_where(&hv.query_equals(3)), 0);
+    }
+
+    #[test]
+    fn test_index_set_query() {
+        let v = vec![true, false, false, true, true, false, false, false, true];
+        let hv: HiVec<_, 3, 2> = HiVec::new(v);
+        let set = IndexSetQuery::from_sorted_indices(vec![1, 3, 7, 8], 9);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![1, 3, 7, 8]);
+        assert!(set.hiquery(1, 0));
+        assert!(!set.hiquery(1, 2));
+        assert!(set.hiquery(2, 1));
+        let gaps = IndexSetQuery::<3, 2>::from_sorted_indices(vec![0, 9], 12);
+        assert!(!gaps.hiquery(2, 1));
+        assert!(gaps.hiquery(2, 2));
+        assert!(set.hiquery(3, 1));
+        let both = hv.query_equals(true).rc().and(set.clone().rc());
+        assert_eq!(both.iter().collect::<Vec<_>>(), vec![3, 8]);
+        let either = hv.query_equals(true).rc().or(set.rc());
+        assert_eq!(either.iter().collect::<Vec<_>>(), vec![0, 1, 3, 4, 7, 8]);
+        assert_eq!(
+            either.collect_with(TraversalStrategy::TopDownRecurse),
+            vec![0, 1, 3, 4, 7, 8]
+        );
+    }
+
+    #[test]
+    fn test_reserve() {
+        let mut hv: HiVec<u32, 3, 4> = HiVec::new(vec![1, 2, 3]);
+        hv.reserve(1000);
+        assert!(hv.capacity() >= 1003);
+        let capacity = hv.capacity();
+        let capacities = hv.layer_capacities();
+        assert!(capacities[0] >= 251 && capacities[1] >= 63 && capacities[2] >= 16);
+        for i in 0..1000 {
+            hv.push(i);
+            assert_eq!(hv.capacity(), capacity);
+            assert_eq!(hv.layer_capacities(), capacities);
+        }
+        let rebuilt: HiVec<u32, 3, 4> = HiVec::new(hv.as_table().to_vec());
+        assert!(same_queries(&hv, &rebuilt, &[0, 2, 999]));
+    }
+
+    #[test]
+    fn test_layer_mismatches() {
+        let v: Vec<u32> = (0..16).collect();
+        let mut hv: HiVec<_, 3, 2> = HiVec::new(v);
+        assert!((0..3).all(|l| hv.layer_mismatches(l).is_empty()));
+        hv.set_unrepaired(13, 40);
+        // Repairing the wrong range leaves the edited block stale.
+        hv.repair_invariant(0..=1);
+        assert_eq!(
+            hv.layer_mismatches(0),
+            vec![(6, LatticeRange::new(13, 12), LatticeRange::new(40, 12))]
+        );
+        assert_eq!(
+            hv.layer_mismatches(1),
+            vec![(3, LatticeRange::new(15, 12), LatticeRange::new(40, 12))]
+        );
+        assert_eq!(
+            hv.layer_mismatches(2),
+            vec![(1, LatticeRange::new(15, 8), LatticeRange::new(40, 8))]
+        );
+        hv.repair_invariant(13..=13);
+        assert!((0..3).all(|l| hv.layer_mismatches(l).is_empty()));
+    }
+
+    #[test]
+    fn test_query_equals_many() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+        let hash = |x: &FreeL32| {
+            let mut h = DefaultHasher::new();
+            x.hash(&mut h);
+            h.finish()
+        };
+        let a = FreeL32::new(0b10);
+        let b = FreeL32::generator(1);
+        assert_eq!(a, b);
+        assert_eq!(a.canonical(), b.canonical());
+        assert_eq!(hash(&a), hash(&b));
+
+        let hv: HiVec<FreeL32, 2, 4> =
+            HiVec::new([0b10, 0b01, 0b10, 0b11].map(FreeL32::new).to_vec());
+        let batch = hv.query_equals_many([a, FreeL32::new(0b11), b]);
+        assert_eq!(batch, vec![(a, vec![0, 2]), (FreeL32::new(0b11), vec![3])]);
+    }
+
+    #[test]
+    fn test_disk_backed_hivec() {
+        // Path::join is named explicitly, since Lattice::join also applies to PathBuf.
+        let name = format!("latticequeries-disk-{}.layers", std::process::id());
+        let path = std::path::Path::join(&std::env::temp_dir(), name);
+        // Removes the file even when an assertion below fails.
+        struct RemoveOnDrop(std::path::PathBuf);
+        impl Drop for RemoveOnDrop {
+            fn drop(&mut self) {
+                let _ = std::fs::remove_file(&self.0);
+            }
+        }
+        let _guard = RemoveOnDrop(path.clone());
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let table: Vec<u32> = (0..300).map(|i| (i * 37 % 101) / 7).collect();
+        let hv: HiVec<u32, 3, 4> = HiVec::new(table.clone());
+        let disk = DiskBackedHiVec::new(HiVec::<u32, 3, 4>::new(table), 2, file).unwrap();
+        assert_eq!(disk.disk_layers(), 2);
+        assert_eq!(disk.len(), hv.len());
+        for x in 0..16 {
+            assert!(hv.query_equals(x).iter().eq(disk.query_equals(x).iter()));
+            let r = LatticeRange::new(x + 2, x);
+            assert!(hv.query_range(r).iter().eq(disk.query_range(r).iter()));
+        }
+        for l in 0..3 {
+            let size = 4usize.pow(l as u32 + 1);
+            for (b, chunk) in hv.as_table().chunks(size).enumerate() {
+                let expected =
+                    LatticeRange::new(*chunk.iter().max().unwrap(), *chunk.iter().min().unwrap());
+                assert_eq!(disk.layer_range(l, b).unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_iter_desc() {
+        let v: Vec<u32> = (0..200)
+            .map(|i| if i % 13 == 0 || i == 199 { 1 } else { 0 })
+            .collect();
+        let hv: HiVec<u32, 2, 4> = HiVec::new(v);
+        let q = hv.query_equals(1);
+        let mut expected: Vec<usize> = q.iter().collect();
+        expected.reverse();
+        assert!(q.iter_desc().eq(expected));
+        assert_eq!(q.iter_desc().next(), Some(199));
+        assert_eq!(hv.query_equals(2).iter_desc().next(), None);
+        let empty: HiVec<u32, 2, 4> = HiVec::new(vec![]);
+        assert_eq!(empty.query_equals(0).iter_desc().count(), 0);
+    }
+
+    #[test]
+    fn test_time_tagged() {
+        let a = TimeTagged::new(3, FreeL32::new(0b01));
+        let b = TimeTagged::new(5, FreeL32::new(0b10));
+        assert_eq!(a.partial_cmp(&b), None);
+        assert!(a < TimeTagged::new(4, FreeL32::new(0b11)));
+        assert_eq!(a.join(b), TimeTagged::new(5, FreeL32::new(0b11)));
+        assert_eq!(a.meet(b), TimeTagged::new(3, FreeL32::new(0)));
+
+        // Blocks of 4: times rise by one per row, tag 0b100 only in rows 8..12.
+        let table: Vec<TimeTagged> = (0..64)
+            .map(|i| {
+                let tags = if (8..12).contains(&i) { 0b101 } else { 0b001 };
+                TimeTagged::new(i, FreeL32::new(tags))
+            })
+            .collect();
+        let hv: HiVec<TimeTagged, 2, 4> = HiVec::new(table);
+        let inner = hv.query_time_and_tags(LatticeRange::new(40, 0), FreeL32::new(0b100));
+        let q = Probed::new(&inner);
+        assert_eq!(q.iter().collect::<Vec<_>>(), vec![8, 9, 10, 11]);
+        assert!(q.take() < 32);
+        let q = hv.query_time_and_tags(LatticeRange::new(30, 20), FreeL32::new(0b100));
+        assert!(!(0..16).any(|b| q.hiquery(1, b)));
+        let q = hv.query_time_and_tags(LatticeRange::new(30, 20), FreeL32::new(0b001));
+        assert_eq!(q.iter().collect::<Vec<_>>(), (20..=30).collect::<Vec<_>>());
+        assert!(!q.hiquery(1, 0) && q.hiquery(1, 5) && !q.hiquery(1, 8));
+    }
+
+    #[cfg(feature = "instrumented")]
+    #[test]
+    fn test_instrumented_query() {
+        use crate::instrumented::InstrumentedQuery;
+        // 4^4 elements, so the top layer is a single block.
+        let hv: HiVec<u32, 4, 4> = HiVec::new(vec![0; 256]);
+        let q = InstrumentedQuery::new(hv.query_equals(1));
+        assert_eq!(q.count(), 0);
+        let (query_at, hiquery) = q.probe_counts();
+        assert!(query_at + hiquery <= 2 * 4 + 1);
+        q.reset();
+        assert_eq!(q.probe_counts(), (0, 0));
+
+        // A block known to be full is counted without visiting its elements.
+        let q = InstrumentedQuery::new(hv.query_equals(0));
+        assert_eq!(q.count(), 256);
+        assert_eq!(q.probe_counts(), (0, 2));
+        q.reset();
+        assert_eq!(q.iter().count(), 256);
+        assert_eq!(q.probe_counts().0, 256);
+    }
+
+    #[test]
+    fn test_clamp() {
+        let r = LatticeRange::new(10, 3);
+        assert_eq!(r.clamp(0), 3);
+        assert_eq!(r.clamp(7), 7);
+        assert_eq!(r.clamp(20), 10);
+
+        let r = LatticeRange::new(FreeL32::new(0b0111), FreeL32::new(0b0001));
+        assert_eq!(r.clamp(FreeL32::new(0b0011)), FreeL32::new(0b0011));
+        assert_eq!(r.clamp(FreeL32::new(0)), FreeL32::new(0b0001));
+        assert_eq!(r.clamp(FreeL32::new(0b1111)), FreeL32::new(0b0111));
+        // Incomparable to both bounds: drops bits above top, adds those of bottom.
+        let y = r.clamp(FreeL32::new(0b1010));
+        assert_eq!(y, FreeL32::new(0b0011));
+        assert!(r.contains(&y));
+    }
+
+    #[test]
+    fn test_recent_change() {
+        let mut v = vec![true, false, false, true, true, false, false, false, true];
+        v.extend([true; 60]);
+        v.extend([false, true]);
+        let hv: HiVec<bool, 2, 4> = HiVec::new(v.clone());
+        let transitions: Vec<usize> = (1..v.len()).filter(|&i| v[i] != v[i - 1]).collect();
+        assert_eq!(
+            hv.query_transitions().iter().collect::<Vec<_>>(),
+            transitions
+        );
+        assert_eq!(transitions, vec![1, 3, 5, 8, 69, 70]);
+
+        for k in [1, 3] {
+            let expected: Vec<usize> = (0..v.len())
+                .filter(|&i| transitions.iter().any(|&j| j <= i && j + k > i))
+                .collect();
+            let q = hv.query_recent_change(k);
+            assert_eq!(q.iter().collect::<Vec<_>>(), expected);
+            assert_eq!(q.collect_with(TraversalStrategy::TopDownRecurse), expected);
+        }
+        let mut expected: Vec<usize> = (1..=10).collect();
+        expected.extend([69, 70]);
+        assert_eq!(
+            hv.query_recent_change(3).iter().collect::<Vec<_>>(),
+            expected
+        );
+        let inner = hv.query_transitions();
+        let q = Probed::new(&inner);
+        assert_eq!(q.findnext(12), Some(69));
+        assert!(q.take() < 20);
+
+        // FANOUT^4 overflows usize, so the top block's span must saturate.
+        let wide: HiVec<bool, 4, { 1 << 20 }> = HiVec::new(v.clone());
+        assert!(wide.query_transitions().hiquery(4, 0));
+        assert!(wide.query_recent_change(3).hiquery(4, 0));
+    }
+
+    #[test]
+    fn test_popcount_bounds() {
+        let table: Vec<FreeL32> = (0..64u32)
+            .map(|i| FreeL32::new(0b1 | (i * 0x9e37) & 0xff0))
+            .collect();
+        let hv: HiVec<FreeL32, 2, 4> = HiVec::new(table.clone());
+        for layer in 0..2 {
+            let size = 4usize.pow(layer as u32 + 1);
+            for (b, chunk) in table.chunks(size).enumerate() {
+                let (lo, hi) = hv.popcount_bounds(layer, b);
+                assert!(chunk
+                    .iter()
+                    .all(|x| lo <= x.popcount() && x.popcount() <= hi));
+                assert!(lo >= 1);
+            }
+        }
+        let uniform: HiVec<AlphaNumSet, 1, 4> = HiVec::new(vec![AlphaNumSet::new("abc"); 4]);
+        assert_eq!(uniform.popcount_bounds(0, 0), (3, 3));
+    }
+
+    #[test]
+    fn test_collect_budgeted() {
+        let v: Vec<u32> = (0..256).map(|i| (i % 5 == 0) as u32).collect();
+        let hv: HiVec<u32, 3, 4> = HiVec::new(v);
+        let q = hv.query_equals(1);
+        let all: Vec<usize> = q.iter().collect();
+
+        let (partial, complete) = q.collect_budgeted(20);
+        assert!(!complete);
+        assert!(!partial.is_empty() && partial.len() < all.len());
+        assert_eq!(partial[..], all[..partial.len()]);
+
+        assert_eq!(q.collect_budgeted(10_000), (all, true));
+        assert_eq!(q.collect_budgeted(0), (vec![], false));
+        let empty: HiVec<u32, 3, 4> = HiVec::new(vec![]);
+        assert_eq!(empty.query_equals(1).collect_budgeted(0), (vec![], true));
+
+        // Once the budget runs out the traversal ends, rather than going on to skip
+        // every remaining block, or scan every remaining element on the linear path.
+        let huge = ConstQuery::<3, 4>::new(false, usize::MAX / 2);
+        assert_eq!(huge.collect_budgeted(10), (vec![], false));
+        let linear = Probed::with_threshold(&huge, usize::MAX);
+        assert_eq!(linear.collect_budgeted(10), (vec![], false));
+        assert_eq!(linear.take(), 10);
+    }
+
+    #[test]
+    fn test_trivial_lattices() {
+        let hv: HiVec<(), 2, 2> = HiVec::new(vec![(); 7]);
+        assert_eq!(hv.query_equals(()).count(), 7);
+        assert!((0..2).all(|b| hv.block_is_uniform(1, b)));
+        fn is_trivial<L: BoundedLattice>() -> bool {
+            L::TOP == L::BOT
+        }
+        assert!(is_trivial::<()>() && is_trivial::<Singleton>());
+        assert!(!is_trivial::<FreeL32>());
+
+        assert_eq!(Singleton.meet(Singleton), Singleton);
+        let hv: HiVec<Singleton, 2, 2> = HiVec::new(vec![Singleton; 5]);
+        let r = LatticeRange::new(Singleton::TOP, Singleton::BOT);
+        assert!(hv.query_range(r).iter().eq(0..5));
+    }
+
+    #[test]
+    fn test_explain() {
+        let v = vec![true, false, false, true, true, false, false, false, true];
+        let hv: HiVec<bool, 2, 2> = HiVec::new(v);
+        let hv2: HiVec<u32, 2, 2> = HiVec::new((0..9).collect());
+        let q = hv
+            .query_equals(true)
+            .rc()
+            .and(hv2.query_range(LatticeRange::new(3, 0)).rc());
+        assert_eq!(q.explain(3), "AND = true");
+        assert_eq!(q.explain(4), "AND failed: right(RangeQuery = false)");
+        assert_eq!(q.explain(1), "AND failed: left(EqualsQuery = false)");
+        assert_eq!(
+            q.explain(5),
+            "AND failed: left(EqualsQuery = false), right(RangeQuery = false)"
+        );
+        let q = q.rc().or(hv.query_equals(true).rc());
+        assert_eq!(q.explain(4), "OR = true: right(EqualsQuery = true)");
+        assert!(q.explain(5).starts_with("OR failed: left(AND failed: "));
+    }
+
+    #[test]
+    fn test_flattened_combinators() {
+        let v = vec![true, false, false, true, true, false, false, false, true];
+        let hv: HiVec<bool, 2, 2> = HiVec::new(v.clone());
+        let hv2: HiVec<u32, 2, 2> = HiVec::new((0..9).collect());
+        let mut all = IntersectionQuery::new(9);
+        assert_eq!(all.count(), 9);
+        all.push(hv.query_equals(true));
+        all.push(hv2.query_range(LatticeRange::new(7, 2)));
+        all.push(hv2.query_range(LatticeRange::new(8, 4)));
+        assert_eq!(all.iter().collect::<Vec<_>>(), vec![4]);
+
+        let mut any = UnionQuery::new(9);
+        assert_eq!(any.count(), 0);
+        any.push(hv2.query_equals(2));
+        any.push(hv2.query_equals(6));
+        any.push(all);
+        assert_eq!(any.iter().collect::<Vec<_>>(), vec![2, 4, 6]);
+    }
+
+    #[test]
+    fn test_longest_run() {
+        let mut v = vec![true, false, false, true, true, false, false, false, true];
+        v.extend([false; 3]);
+        v.extend([true; 37]);
+        v.extend([false, true, true]);
+        let hv: HiVec<bool, 2, 4> = HiVec::new(v.clone());
+        assert_eq!(hv.query_equals(true).longest_run(), Some((12, 37)));
+        assert_eq!(hv.query_equals(false).longest_run(), Some((5, 3)));
+        let inner = hv.query_equals(true);
+        let q = Probed::new(&inner);
+        assert_eq!(q.longest_run(), Some((12, 37)));
+        assert!(q.take() < 37);
+
+        let short: HiVec<bool, 2, 4> = HiVec::new(v[..9].to_vec());
+        assert_eq!(short.query_equals(true).longest_run(), Some((3, 2)));
+        let r = LatticeRange::new(5, 2);
+        let nums: HiVec<u32, 2, 4> = HiVec::new((0..40).map(|i| i % 20).collect());
+        assert_eq!(nums.query_range(r).longest_run(), Some((2, 4)));
+        assert_eq!(nums.query_equals(99).longest_run(), None);
+    }
+
+    #[cfg(feature = "smallvec")]
+    #[test]
+    fn test_inline_layers() {
+        let v: Vec<u32> = (0..100).map(|i| i % 7).collect();
+        let hv: HiVec<u32, 4, 2> = HiVec::new(v.clone());
+        assert!(hv.layers_inline());
+        assert!(hv
+            .query_equals(3)
+            .iter()
+            .eq((0..100).filter(|i| i % 7 == 3)));
+        let deep: HiVec<u32, 6, 2> = HiVec::new(v);
+        assert!(!deep.layers_inline());
+        assert!(same_queries(&hv, &deep, &[0, 3, 6]));
+    }
+
+    #[test]
+    fn test_get_mut_guard() {
+        let mut hv: HiVec<u32, 2, 2> = HiVec::new((0..8).collect());
+        assert!(hv.get_mut(8).is_none());
+        {
+            let mut x = hv.get_mut(5).unwrap();
+            *x += 10;
+            *x += 10;
+        }
+        assert_eq!(hv.get(5), Some(&25));
+        assert!((0..2).all(|l| hv.layer_mismatches(l).is_empty()));
+        assert_eq!(hv.query_equals(25).iter().collect::<Vec<_>>(), vec![5]);
+
+        // Writing back the value it started with is not a change, so no repair.
+        hv.set_unrepaired(2, 40);
+        {
+            let mut x = hv.get_mut(2).unwrap();
+            *x = 7;
+            *x = 40;
+        }
+        assert!(!hv.layer_mismatches(0).is_empty());
+        *hv.get_mut(2).unwrap() = 41;
+        assert!((0..2).all(|l| hv.layer_mismatches(l).is_empty()));
+    }
+
+    #[test]
+    fn test_co_occurrence() {
+        let a: Vec<bool> = (0..70).map(|i| i < 32 || i % 9 == 0).collect();
+        let b: Vec<u32> = (0..70).map(|i| (16..48).contains(&i) as u32).collect();
+        let ha: HiVec<bool, 2, 4> = HiVec::new(a.clone());
+        let hb: HiVec<u32, 2, 4> = HiVec::new(b.clone());
+        let (qa, qb) = (ha.query_equals(true), hb.query_equals(1));
+        let mut expected = CoOccurStats::default();
+        for i in 0..70 {
+            match (a[i], b[i] == 1) {
+                (true, true) => expected.both += 1,
+                (true, false) => expected.only_a += 1,
+                (false, true) => expected.only_b += 1,
+                (false, false) => expected.neither += 1,
+            }
+        }
+        assert_eq!(co_occurrence(&qa, &qb), expected);
+        assert_eq!(expected.both, 16 + 2);
+
+        let pa = Probed::new(&qa);
+        let pb = Probed::new(&qb);
+        co_occurrence(&pa, &pb);
+        assert!(pa.take() + pb.take() < 2 * 70);
+
+        // A uniform top block is counted from its span, which saturates at FANOUT^4.
+        let wide: HiVec<bool, 4, { 1 << 20 }> = HiVec::new(vec![true; 10]);
+        let stats = co_occurrence(&wide.query_equals(true), &wide.query_equals(false));
+        assert_eq!(
+            (stats.only_a, stats.both, stats.only_b, stats.neither),
+            (10, 0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn test_reachability() {
+        // a -> b -> d, a -> c -> d, e alone. Each row lists a node and its ancestors.
+        let rows = [
+            vec!["a"],
+            vec!["b", "a"],
+            vec!["c", "a"],
+            vec!["d", "b", "c", "a"],
+            vec!["e"],
+        ];
+        let mut builder = ReachabilityBuilder::new();
+        for _ in 0..4 {
+            for row in &rows {
+                builder.push_row(row.iter().copied());
+            }
+        }
+        assert_eq!(builder.node_bit("c"), 2);
+        let reach = builder.build::<2, 4>();
+        assert_eq!(reach.len(), 20);
+        let from = |node| {
+            reach
+                .query_reachable_from(&node)
+                .map(|q| q.iter().map(|i| rows[i % 5][0]).collect::<Vec<_>>())
+        };
+        assert_eq!(from("b").unwrap()[..2], ["b", "d"]);
+        assert_eq!(from("a").unwrap().len(), 16);
+        assert_eq!(from("d").unwrap(), ["d"; 4]);
+        assert_eq!(from("e").unwrap(), ["e"; 4]);
+        assert_eq!(from("z"), None);
+        let q = reach.query_reachable_from(&"e").unwrap();
+        assert!(!q.hiquery(1, 0) && q.hiquery(1, 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "More than 32 nodes")]
+    fn test_reachability_too_many_nodes() {
+        let mut builder = ReachabilityBuilder::new();
+        for node in 0..33 {
+            builder.node_bit(node);
+        }
+    }
+
+    #[test]
+    fn test_materialize() {
+        let hv: HiVec<u32, 2, 4> = HiVec::new((0..50).map(|i| i % 11).collect());
+        let q = hv
+            .query_range(LatticeRange::new(6, 2))
+            .rc()
+            .and(hv.query_equals(4).rc().or(hv.query_equals(6).rc()).rc());
+        let m = q.materialize();
+        assert_eq!(m.len(), 50);
+        assert!(m.query_equals(true).iter().eq(q.iter()));
+        assert_eq!(m.query_equals(true).count(), 9);
+        assert!(m
+            .query_equals(false)
+            .iter()
+            .eq((0..50).filter(|&i| !q.query_at(i))));
+    }
+
+    #[test]
+    fn test_partial_top_block() {
+        // 4^3 = 64 leaves per top block, so these lengths all end in a partial one.
+        for len in [65, 70, 100, 127, 129, 200] {
+            let v: Vec<u32> = (0..len)
+                .map(|i| (i % 23 == 22 || i == len - 1) as u32)
+                .collect();
+            let hv: HiVec<u32, 3, 4> = HiVec::new(v.clone());
+            let q = hv.query_equals(1);
+            let expected: Vec<usize> = (0..len as usize).filter(|&i| v[i] == 1).collect();
+            assert_eq!(q.iter().collect::<Vec<_>>(), expected);
+            assert_eq!(q.findnext(len as usize - 1), Some(len as usize - 1));
+            for i in 0..len as usize {
+                assert_eq!(q.findnext(i), expected.iter().copied().find(|&j| j >= i));
+            }
+        }
+    }
+
+    #[test]
+    fn test_remap_query() {
+        let table: Vec<u32> = (0..80).map(|i| i % 10).collect();
+        let hv: HiVec<u32, 2, 4> = HiVec::new(table.clone());
+        // Retain the rows not divisible by 3, remembering where each came from.
+        let kept: Vec<usize> = (0..80).filter(|i| i % 3 != 0).collect();
+        let compacted: HiVec<u32, 2, 4> = HiVec::new(kept.iter().map(|&i| table[i]).collect());
+
+        let q = RemapQuery::new(hv.query_equals(7), kept.clone());
+        assert_eq!(q.length(), compacted.len());
+        assert!(q.iter().eq(compacted.query_equals(7).iter()));
+        assert!(q
+            .iter()
+            .map(|k| kept[k])
+            .eq((0..80).filter(|i| i % 10 == 7 && i % 3 != 0)));
+        assert!((0..kept.len().div_ceil(4))
+            .all(|b| q.hiquery(1, b) || compacted.query_equals(7).iter().all(|k| k / 4 != b)));
+
+        // Probing an empty block only searches the old span that block maps to, even
+        // when the next old match is far away.
+        let mut sparse = vec![0u32; 8192];
+        sparse[8000] = 1;
+        let hv: HiVec<u32, 3, 4> = HiVec::new(sparse);
+        let inner = hv.query_equals(1);
+        let q = RemapQuery::new(Probed::new(&inner), (0..8192).step_by(2).collect());
+        assert!(!q.hiquery(1, 0));
+        assert!(q.inner().take() < 20);
+        assert!(q.hiquery(1, 1000));
+        // A span far past length must not overflow.
+        assert!(!HiQuery::<3, 4>::hiquery(
+            &RemapQuery::new(hv.query_equals(1), vec![0]),
+            60,
+            0
+        ));
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let hv: HiVec<u32, 2, 4> = HiVec::new((0..60).map(|i| i % 7).collect());
+        let inner = hv.query_equals(3);
+        let q = Probed::new(&inner);
+        let result = q.evaluate();
+        assert_eq!(result.length(), 60);
+        assert_eq!(q.take(), 0);
+        assert_eq!(result.count(), 9);
+        assert!(q.take() > 0);
+        assert_eq!(
+            result.indices(),
+            (0..60).filter(|i| i % 7 == 3).collect::<Vec<_>>()
+        );
+        assert_eq!(result.count(), 9);
+        let mask = result.mask();
+        assert_eq!(q.take(), 0);
+        assert_eq!(mask, q.mask());
+    }
+
+    #[test]
+    fn test_tropical_cost() {
+        let (a, b) = (TropicalCost::new(3), TropicalCost::new(5));
+        assert_eq!(a.combine(b), TropicalCost::new(8));
+        assert_eq!(a.meet(b), a);
+        assert_eq!(a.join(b), b);
+        let near_max = TropicalCost::new(u64::MAX - 1);
+        assert!(near_max.combine(b).is_infinite());
+        assert!(TropicalCost::INFINITY.combine(a).is_infinite());
+        assert_eq!(TropicalCost::TOP, TropicalCost::INFINITY);
+
+        let costs: Vec<TropicalCost> = (0..64u64)
+            .map(|i| {
+                if i < 32 {
+                    TropicalCost::INFINITY
+                } else {
+                    TropicalCost::new(i)
+                }
+            })
+            .collect();
+        let hv: HiVec<TropicalCost, 2, 4> = HiVec::new(costs);
+        let inner = hv.query_cost_leq(TropicalCost::new(40));
+        assert!(inner.iter().eq(32..=40));
+        let q = Probed::new(&inner);
+        assert_eq!(q.findnext(0), Some(32));
+        assert!(q.take() < 10);
+        assert_eq!(hv.query_cost_leq(TropicalCost::new(31)).count(), 0);
+        assert_eq!(hv.query_cost_leq(TropicalCost::INFINITY).count(), 64);
+    }
+
+    #[test]
+    fn test_merge_streams() {
+        let v = vec![true, false, false, true, true, false, false, false, true];
+        let ha: HiVec<bool, 2, 2> = HiVec::new(v);
+        let hb: HiVec<u32, 2, 2> = HiVec::new(vec![0, 1, 0, 1, 0, 0, 1, 0, 1]);
+        let (qa, qb) = (ha.query_equals(true), hb.query_equals(1));
+        let merged: Vec<(usize, Membership)> = merge_streams(&qa, &qb).collect();
+        assert_eq!(
+            merged,
+            vec![
+                (0, Membership::A),
+                (1, Membership::B),
+                (3, Membership::Both),
+                (4, Membership::A),
+                (6, Membership::B),
+                (8, Membership::Both),
+            ]
+        );
+        let none = hb.query_equals(7);
+        assert!(merge_streams(&qa, &none).all(|(_, m)| m == Membership::A));
+    }
+
+    #[test]
+    fn test_from_runs() {
+        let aligned = vec![(3u32, 8), (5, 16), (3, 4), (9, 40)];
+        let unaligned = vec![(1u32, 3), (7, 0), (2, 1), (4, 10), (1, 2), (8, 37), (2, 5)];
+        for runs in [aligned, unaligned, vec![]] {
+            let expanded: Vec<u32> = runs
+                .iter()
+                .flat_map(|&(v, n)| std::iter::repeat_n(v, n))
+                .collect();
+            let from_runs: HiVec<u32, 3, 4> = HiVec::from_runs(runs);
+            let built: HiVec<u32, 3, 4> = HiVec::new(expanded);
+            assert!(same_data(&from_runs, &built));
+            assert!((0..3).all(|l| from_runs.layer_mismatches(l).is_empty()));
+            assert!(same_queries(&from_runs, &built, &[1, 2, 3, 4, 5, 8, 9]));
+        }
+    }
+
+    #[test]
+    fn test_nearest_matches() {
+        let v = vec![true, false, false, true, true, false, false, false, true];
+        let hv: HiVec<bool, 2, 2> = HiVec::new(v);
+        let q = hv.query_equals(true);
+        assert_eq!(q.nearest_matches(6, 2), vec![4, 8]);
+        assert_eq!(q.nearest_matches(6, 10), vec![4, 8, 3, 0]);
+        // 1 is as far from 0 as from 2, the lower index wins.
+        let hv2: HiVec<bool, 2, 2> = HiVec::new(vec![true, false, true, false]);
+        assert_eq!(hv2.query_equals(true).nearest_matches(1, 2), vec![0, 2]);
+        assert_eq!(q.nearest_matches(3, 1), vec![3]);
+        assert_eq!(q.nearest_matches(3, 0), Vec::<usize>::new());
+        assert_eq!(q.nearest_matches(20, 2), vec![8, 4]);
+    }
+
+    #[test]
+    fn test_keyed_payloads() {
+        let a = Keyed::new(FreeL32::new(0b01), "first".to_string());
+        let b = Keyed::new(FreeL32::new(0b01), "second".to_string());
+        assert_eq!(a, b);
+        assert_eq!(a.clone().join(b.clone()).payload, "first");
+        let table: Vec<Keyed<FreeL32, String>> = (0..20)
+            .map(|i| Keyed::new(FreeL32::new(1 << (i / 5)), format!("row {i}")))
+            .collect();
+        let hv: HiVec<Keyed<FreeL32, String>, 2, 4> = HiVec::new(table);
+        let q = hv.query_equals_key(FreeL32::new(0b100));
+        let rows: Vec<&str> = q.iter().map(|i| hv.payload(i).unwrap().as_str()).collect();
+        assert_eq!(rows, ["row 10", "row 11", "row 12", "row 13", "row 14"]);
+        assert!(!q.hiquery(1, 0) && q.hiquery(1, 2) && !q.hiquery(1, 4));
+        assert_eq!(hv.query_equals_key(FreeL32::new(0b11)).count(), 0);
+    }
+
+    #[test]
+    fn test_longer_than_top_layer() {
+        let hv: HiVec<u32, 2, 2> = HiVec::new(vec![0, 1, 0, 0, 1, 1]);
+        let q = hv.query_equals(1);
+        assert_eq!(q.iter().collect::<Vec<_>>(), vec![1, 4, 5]);
+        assert_eq!(q.findnext(2), Some(4));
+        assert_eq!(q.findprev(3), Some(1));
+
+        // Far past FANOUT^N, with matches only in the last of many top blocks.
+        let mut v = vec![0u32; 1000];
+        v[997] = 1;
+        let hv: HiVec<u32, 2, 2> = HiVec::new(v);
+        let inner = hv.query_equals(1);
+        let q = Probed::new(&inner);
+        assert_eq!(q.iter().collect::<Vec<_>>(), vec![997]);
+        assert!(q.take() < 1000);
+    }
+
+    #[test]
+    fn test_new_single_pass() {
+        for len in [0usize, 1, 5, 16, 63, 64, 65, 200, 1000] {
+            let table: Vec<u32> = (0..len as u32).map(|i| (i * 7919) % 113).collect();
+            let single: HiVec<u32, 3, 4> = HiVec::new_single_pass(table.clone());
+            let built: HiVec<u32, 3, 4> = HiVec::new(table);
+            assert!((0..3).all(|l| single.layer_mismatches(l).is_empty()));
+            assert!(same_queries(&single, &built, &[0, 5, 112]));
+            // Each layer got its exact size up front, so it was never reallocated.
+            let sizes: Vec<usize> = (1..=3u32).map(|l| len.div_ceil(4usize.pow(l))).collect();
+            assert_eq!(single.layer_capacities(), sizes);
+
+            let single: HiVec<u32, 0, 4> = HiVec::new_single_pass(built.as_table().to_vec());
+            let flat: HiVec<u32, 0, 4> = HiVec::new(built.as_table().to_vec());
+            assert!(single.into_parts() == flat.into_parts());
+            assert!(
+                built.clone().into_parts()
+                    == HiVec::<u32, 3, 4>::new_single_pass(built.as_table().to_vec()).into_parts()
+            );
+        }
+    }
+
+    #[test]
+    fn test_iter_with_cause() {
+        let a: HiVec<u32, 3, 4> = HiVec::new((0..300).map(|i| i % 17).collect());
+        let b: HiVec<u32, 3, 4> = HiVec::new((0..300).map(|i| 100 + i % 23).collect());
+        let range = LatticeRange::new(112, 110);
+
+        let leaf = b.query_range(range);
+        for (i, x) in leaf.iter_with_cause() {
+            assert_eq!(Some(x), b.get(i));
+            assert!(range.contains(x));
+        }
+
+        let either = a.query_equals(3).rc().or(b.query_range(range).rc());
+        let caused: Vec<(usize, u32)> = either.iter_with_cause().map(|(i, x)| (i, *x)).collect();
+        assert_eq!(
+            caused.iter().map(|&(i, _)| i).collect::<Vec<_>>(),
+            either.iter().collect::<Vec<_>>()
+        );
+        for &(i, x) in &caused {
+            // The left branch wins when both match, and its value comes from column a.
+            if a.get(i) == Some(&3) {
+                assert_eq!(x, 3);
+            } else {
+                assert_eq!(Some(&x), b.get(i));
+                assert!(range.contains(&x));
+            }
+        }
+        assert!(caused.iter().any(|&(_, x)| x == 3));
+        assert!(caused.iter().any(|&(_, x)| x >= 110));
+
+        let both = b.query_range(range).rc().and(a.query_equals(3).rc());
+        for (i, x) in both.iter_with_cause() {
+            assert_eq!(a.get(i), Some(&3));
+            assert_eq!(Some(x), b.get(i));
+        }
+    }
+
+    #[test]
+    fn test_layer_tops_use_join() {
+        let table: Vec<FreeL32> = (0..32)
+            .map(|i| FreeL32::new((1 << (16 + i % 8)) | 1))
+            .collect();
+        let hv: HiVec<FreeL32, 2, 4> = HiVec::new(table.clone());
+        let range = LatticeRange::new(FreeL32::new((1 << 17) | (1 << 16) | 1), FreeL32::new(1));
+        let naive: Vec<usize> = (0..table.len())
+            .filter(|&i| range.contains(&table[i]))
+            .collect();
+        assert_eq!(hv.query_range(range).iter().collect::<Vec<_>>(), naive);
+
+        let (_, layers) = hv.into_parts();
+        let r = layers[0][0];
+        assert_eq!(r.top().canonical(), 0b1111 << 16 | 1);
+        assert_eq!(r.bottom().canonical(), 1);
+        assert!(r.top().canonical() & !r.bottom().canonical() != 0);
+    }
+
+    #[test]
+    fn test_range_isempty() {
+        let f = FreeL32::new;
+        assert!(!LatticeRange::new(f(0b0111), f(0b0001)).isempty());
+        assert!(!LatticeRange::singleton(f(0b0101)).isempty());
+        assert!(LatticeRange::new(f(0b0001), f(0b0111)).isempty());
+        // Incomparable bounds describe no elements at all.
+        assert!(LatticeRange::new(f(0b0110), f(0b0001)).isempty());
+        assert!(LatticeRange::new(f(0b0011), f(0b0101))
+            .intersect(LatticeRange::new(f(0b0111), f(0b0000)))
+            .isempty());
+
+        // RangeQuery can descend only into blocks whose intersection is nonempty.
+        let table: Vec<FreeL32> = (0..64)
+            .map(|i| f(if i < 32 { 0b0011 } else { 0b1100 }))
+            .collect();
+        let hv: HiVec<FreeL32, 2, 4> = HiVec::new(table);
+        let low = hv.query_range(LatticeRange::new(f(0b0111), f(0b0001)));
+        assert_eq!(low.iter().collect::<Vec<_>>(), (0..32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_range_contains_bounds() {
+        let f = FreeL32::new;
+        let r = LatticeRange::new(f(0b0111), f(0b0001));
+        assert!(r.contains(&f(0b0011)));
+        assert!(r.contains(&f(0b0001)) && r.contains(&f(0b0111)));
+        assert!(!r.contains(&f(0b0000)));
+        assert!(!r.contains(&f(0b1111)));
+        assert!(!r.contains(&f(0b0110)));
+
+        let table: Vec<FreeL32> = (0..40).map(|i| f(i % 16)).collect();
+        let hv: HiVec<FreeL32, 2, 4> = HiVec::new(table.clone());
+        let naive: Vec<usize> = (0..40).filter(|&i| table[i] == f(0b0011)).collect();
+        assert_eq!(hv.query_equals(f(0b0011)).iter().collect::<Vec<_>>(), naive);
+        let naive: Vec<usize> = (0..40).filter(|&i| r.contains(&table[i])).collect();
+        assert_eq!(naive.len(), 12);
+        assert_eq!(hv.query_range(r).iter().collect::<Vec<_>>(), naive);
+    }
+
+    #[test]
+    fn test_free_bitset_laws() {
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        let mut random = move || {
+            let mut words = [0u64; 3];
+            for w in &mut words {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                // Sparse words, so that some pairs end up comparable.
+                *w = state & (state >> 11) & (state >> 23);
+            }
+            FreeBitset::new(words)
+        };
+        for _ in 0..200 {
+            let (a, b, c) = (random(), random(), random());
+            assert_eq!(a.join(a), a);
+            assert_eq!(a.join(b), b.join(a));
+            assert_eq!(a.join(b).join(c), a.join(b.join(c)));
+            assert_eq!(a.meet(a.join(b)), a);
+            assert!(a <= a.join(b) && a.meet(b) <= a);
+            assert_eq!(a.partial_cmp(&a.complement()), None);
+            assert_eq!(a.join(a.complement()), FreeBitset::TOP);
+            assert_eq!(a.meet(a.complement()), FreeBitset::BOT);
+        }
+        let g = FreeBitset::<3>::generator(150);
+        assert_eq!(g.words, [0, 0, 1 << 22]);
+        assert_eq!(g.popcount(), 1);
+
+        let x = FreeL128::new(1 << 100 | 0b101);
+        let y = FreeL128::new(1 << 100 | 0b100);
+        let (bx, by) = (FreeBitset::from(x), FreeBitset::from(y));
+        assert_eq!(x.partial_cmp(&y), bx.partial_cmp(&by));
+        assert_eq!(FreeBitset::from(x.meet(y)), bx.meet(by));
+    }
+
+    #[test]
+    fn test_range_lattice() {
+        let r = LatticeRange::new;
+        let outer = r(10, 0);
+        let inner = r(7, 3);
+        assert!(inner < outer);
+        assert_eq!(inner.partial_cmp(&inner), Some(std::cmp::Ordering::Equal));
+        // Disjoint and partially overlapping ranges are not nested either way.
+        assert_eq!(r(3, 1).partial_cmp(&r(8, 5)), None);
+        assert_eq!(r(6, 2).partial_cmp(&r(9, 4)), None);
+        assert_eq!(r(6, 2).join(r(9, 4)), r(9, 2));
+        assert_eq!(r(6, 2).meet(r(9, 4)), r(6, 4));
+        assert_eq!(inner.join(outer), outer);
+        assert_eq!(inner.meet(outer), inner);
+
+        let f = FreeL32::new;
+        assert!(LatticeRange::new(f(0b011), f(0b001)) < LatticeRange::new(f(0b111), f(0b001)));
+        assert_eq!(
+            LatticeRange::new(f(0b011), f(0b001))
+                .partial_cmp(&LatticeRange::new(f(0b101), f(0b001))),
+            None
+        );
+
+        // A HiVec of ranges, queried for the ones inside [0, 100] that cover [40, 50].
+        let table: Vec<LatticeRange<u32>> = (0..200).map(|i| r(i + 30, i / 2)).collect();
+        let hv: HiVec<LatticeRange<u32>, 3, 4> = HiVec::new(table.clone());
+        let q = LatticeRange::new(r(100, 0), r(50, 40));
+        let naive: Vec<usize> = (0..200).filter(|&i| q.contains(&table[i])).collect();
+        assert!(!naive.is_empty());
+        assert_eq!(hv.query_range(q).iter().collect::<Vec<_>>(), naive);
+    }
+
+    #[test]
+    fn test_query_not_equals() {
+        let f = FreeL32::new;
+        let mut table: Vec<FreeL32> = vec![f(0b0101); 70];
+        table.extend((0..50).map(|i| f([0b0101, 0b0001, 0b0111, 0b1010][i % 4])));
+        table.extend([f(0b0101); 30]);
+        table[3] = f(0b0100);
+        let hv: HiVec<FreeL32, 3, 4> = HiVec::new(table.clone());
+        for item in [f(0b0101), f(0b0001), f(0b1111)] {
+            let naive: Vec<usize> = (0..table.len()).filter(|&i| table[i] != item).collect();
+            let q = hv.query_not_equals(item);
+            assert_eq!(q.iter().collect::<Vec<_>>(), naive);
+            assert_eq!(
+                q.iter_desc().collect::<Vec<_>>(),
+                naive.iter().rev().copied().collect::<Vec<_>>()
+            );
+            assert_eq!(q.count(), naive.len());
+        }
+    }
+
+    #[test]
+    fn test_leaf_negation() {
+        let f = FreeL32::new;
+        let a: Vec<FreeL32> = (0..200)
+            .map(|i| {
+                f(if i < 64 {
+                    0b0011
+                } else {
+                    [0b0011, 0b0110][i % 2]
+                })
+            })
+            .collect();
+        let b: Vec<u32> = (0..200)
+            .map(|i| {
+                if (100..150).contains(&i) {
+                    7
+                } else {
+                    i as u32 % 13
+                }
+            })
+            .collect();
+        let ha: HiVec<FreeL32, 3, 4> = HiVec::new(a.clone());
+        let hb: HiVec<u32, 3, 4> = HiVec::new(b.clone());
+        let range = LatticeRange::new(9, 5);
+
+        let q1 = ha.query_equals(f(0b0011)).rc();
+        let q2 = hb.query_range(range).rc();
+        let naive: Vec<usize> = (0..200).filter(|&i| a[i] != f(0b0011)).collect();
+        assert_eq!(q1.negation().iter().collect::<Vec<_>>(), naive);
+        let naive: Vec<usize> = (0..200).filter(|&i| !range.contains(&b[i])).collect();
+        assert_eq!(q2.negation().iter().collect::<Vec<_>>(), naive);
+        assert_eq!(
+            q2.negation().rc().negation().iter().collect::<Vec<_>>(),
+            q2.iter().collect::<Vec<_>>()
+        );
+
+        // De Morgan: not (q1 and q2) == (not q1) or (not q2).
+        let negated = q1.clone().and(q2.clone()).rc().negation();
+        let manual = q1.negation().rc().or(q2.negation().rc());
+        let naive: Vec<usize> = (0..200)
+            .filter(|&i| !(a[i] == f(0b0011) && range.contains(&b[i])))
+            .collect();
+        assert_eq!(negated.iter().collect::<Vec<_>>(), naive);
+        assert_eq!(manual.iter().collect::<Vec<_>>(), naive);
+        assert_eq!(negated.longest_run(), manual.longest_run());
+    }
+
+    #[test]
+    fn test_threshold_queries() {
+        // Mostly increasing, with some noise so that blocks overlap.
+        let table: Vec<u32> = (0..500).map(|i| i / 2 + (i * 37) % 11).collect();
+        let hv: HiVec<u32, 3, 4> = HiVec::new(table.clone());
+        for bound in [0, 3, 60, 128, 250, 400] {
+            let le: Vec<usize> = (0..500).filter(|&i| table[i] <= bound).collect();
+            let ge: Vec<usize> = (0..500).filter(|&i| table[i] >= bound).collect();
+            assert_eq!(hv.query_le(bound).iter().collect::<Vec<_>>(), le);
+            assert_eq!(hv.query_ge(bound).iter().collect::<Vec<_>>(), ge);
+        }
+        assert_eq!(hv.query_ge(0).longest_run(), Some((0, 500)));
+        assert_eq!(hv.query_le(1000).longest_run(), Some((0, 500)));
+    }
+
+    #[test]
+    fn test_double_ended_iter() {
+        let v: Vec<u32> = (0..500).map(|i| (i * i + 3 * i) % 29).collect();
+        let hv: HiVec<u32, 3, 4> = HiVec::new(v.clone());
+        for item in [0, 5, 28, 100] {
+            let q = hv.query_equals(item);
+            let forward: Vec<usize> = q.iter().collect();
+            let mut backward: Vec<usize> = q.iter().rev().collect();
+            backward.reverse();
+            assert_eq!(forward, backward);
+            assert_eq!(
+                forward,
+                (0..500).filter(|&i| v[i] == item).collect::<Vec<_>>()
+            );
+
+            // Alternate ends until they meet: every match exactly once.
+            let mut it = q.iter();
+            let mut seen = Vec::new();
+            for k in 0.. {
+                match if k % 2 == 0 {
+                    it.next()
+                } else {
+                    it.next_back()
+                } {
+                    Some(j) => seen.push(j),
+                    None => break,
+                }
+            }
+            assert_eq!(it.next(), None);
+            assert_eq!(it.next_back(), None);
+            seen.sort();
+            assert_eq!(seen, forward);
+        }
+    }
+
+    #[test]
+    fn test_hierarchical_count() {
+        let len = 5000;
+        for density in [0, 1, 10, 500, 990, 1000] {
+            // Runs of matches, so dense and sparse regions both show up.
+            let v: Vec<bool> = (0..len)
+                .map(|i| (i * 7 / 64 * 389) % 1000 < density)
+                .collect();
+            let hv: HiVec<bool, 4, 4> = HiVec::new(v.clone());
+            let naive = v.iter().filter(|&&b| b).count();
+            let q = hv.query_equals(true);
+            assert_eq!(q.count(), naive);
+
+            let probed = Probed::new(&q);
+            assert_eq!(probed.count(), naive);
+            let hierarchical = probed.take();
+            assert_eq!(probed.iter().count(), naive);
+            let walked = probed.take();
+            assert!(
+                hierarchical <= walked,
+                "density {density}: {hierarchical} > {walked}"
+            );
+        }
+        let v: Vec<u32> = (0..len as u32).collect();
+        let hv: HiVec<u32, 4, 4> = HiVec::new(v);
+        let q = hv.query_range(LatticeRange::new(4321, 17));
+        assert_eq!(q.count(), 4305);
+        let probed = Probed::new(&q);
+        probed.count();
+        assert!(probed.take() < 200);
+    }
+
+    #[test]
+    fn test_nth() {
+        let mut state = 12345u64;
+        let mut next = move || {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 33) as u32
+        };
+        let v: Vec<u32> = (0..1000)
+            .map(|i| {
+                if (300..600).contains(&i) {
+                    7
+                } else {
+                    next() % 16
+                }
+            })
+            .collect();
+        let hv: HiVec<u32, 3, 4> = HiVec::new(v);
+        for _ in 0..20 {
+            let (a, b) = (next() % 16, next() % 16);
+            let q = hv
+                .query_range(LatticeRange::new(a.max(b), a.min(b)))
+                .rc()
+                .or(hv.query_equals(next() % 16).rc());
+            let all: Vec<usize> = q.iter().collect();
+            for k in
+                (0..all.len() + 3)
+                    .step_by(7)
+                    .chain([0, all.len().saturating_sub(1), all.len()])
+            {
+                assert_eq!(q.nth(k), q.iter().nth(k));
+            }
+        }
+        let q = hv.query_equals(7);
+        let probed = Probed::new(&q);
+        assert_eq!(probed.nth(250), q.iter().nth(250));
+        let skipping = probed.take();
+        probed.iter().nth(250);
+        let walking = probed.take();
+        assert!(skipping < walking, "{skipping} >= {walking}");
+    }
+
+    #[test]
+    fn test_query_by() {
+        let mut words: Vec<&str> = vec!["banana"; 40];
+        words.extend(["cab", "zebra", "abba", "xyz", "bad", "cabbage", "c"].repeat(20));
+        words.extend(["xyz"; 50]);
+        let hv: HiVec<AlphaNumSet, 3, 4> =
+            HiVec::new(words.iter().map(|w| AlphaNumSet::new(w)).collect());
+
+        // Words spelled only with letters from a charset. Some element of a block can
+        // be, only if the block's bottom is.
+        let charset = AlphaNumSet::new("abcn");
+        let spelled = hv.query_by(|x| *x <= charset, |r| *r.bottom() <= charset);
+        let naive: Vec<usize> = (0..words.len())
+            .filter(|&i| words[i].chars().all(|c| "abcn".contains(c)))
+            .collect();
+        assert_eq!(spelled.iter().collect::<Vec<_>>(), naive);
+
+        // Words using every letter of a charset, bounded through the block's top.
+        let needed = AlphaNumSet::new("ab");
+        let using = hv.query_by(|x| *x >= needed, |r| *r.top() >= needed);
+        let naive: Vec<usize> = (0..words.len())
+            .filter(|&i| words[i].contains('a') && words[i].contains('b'))
+            .collect();
+        assert_eq!(using.iter().collect::<Vec<_>>(), naive);
+        // Indices 192..208 all hold "xyz", so both block tests rule that block out.
+        assert!(!spelled.hiquery(2, 12) && !using.hiquery(2, 12));
+    }
+
+    #[test]
+    fn test_push_pop() {
+        fn check<const N: usize>(values: &[u32]) {
+            let mut hv: HiVec<u32, N, 4> = HiVec::new(vec![]);
+            for (n, &x) in values.iter().enumerate() {
+                hv.push(x);
+                let fresh: HiVec<u32, N, 4> = HiVec::new(values[..=n].to_vec());
+                assert_eq!(hv.clone().into_parts(), fresh.into_parts());
+            }
+            for n in (0..values.len()).rev() {
+                assert_eq!(hv.pop(), Some(values[n]));
+                let fresh: HiVec<u32, N, 4> = HiVec::new(values[..n].to_vec());
+                assert_eq!(hv.clone().into_parts(), fresh.into_parts());
+            }
+            assert_eq!(hv.pop(), None);
+            assert!(hv.is_empty());
+        }
+        let values: Vec<u32> = (0..150).map(|i| (i * 31 + i / 7) % 40).collect();
+        check::<3>(&values);
+        check::<0>(&values);
+    }
+
+    #[test]
+    fn test_recompute_all() {
+        let mut hv: HiVec<u32, 3, 4> = HiVec::new((0..300).map(|i| i % 10).collect());
+        let capacities = hv.layer_capacities();
+        let mut scrambled: Vec<u32> = (0..300).map(|i| (i * 7 + 3) % 50).collect();
+        scrambled[123] = 1000;
+        for (i, &x) in scrambled.iter().enumerate() {
+            hv.set_unrepaired(i, x);
+        }
+        assert_ne!(hv.query_equals(1000).iter().collect::<Vec<_>>(), vec![123]);
+        hv.recompute_all();
+        assert_eq!(hv.query_equals(1000).iter().collect::<Vec<_>>(), vec![123]);
+        let fresh: HiVec<u32, 3, 4> = HiVec::new(scrambled);
+        assert!(same_queries(&hv, &fresh, &[0, 3, 49, 1000]));
+        assert_eq!(hv.layer_capacities(), capacities);
+        assert_eq!(hv.into_parts(), fresh.into_parts());
+    }
+
+    #[test]
+    fn test_mutate_many() {
+        let mut table: Vec<u32> = (0..400).map(|i| i % 13).collect();
+        let mut hv: HiVec<u32, 3, 4> = HiVec::new(table.clone());
+        let edits = [
+            (17, 99),
+            (250, 0),
+            (3, 42),
+            (399, 99),
+            (120, 7),
+            (17, 5),
+            (1000, 1),
+        ];
+        hv.mutate_many(edits);
+        for (i, x) in edits {
+            if let Some(t) = table.get_mut(i) {
+                *t = x;
+            }
+        }
+        let fresh: HiVec<u32, 3, 4> = HiVec::new(table.clone());
+        assert_eq!(hv.clone().into_parts(), fresh.into_parts());
+        assert_eq!(hv.query_equals(99).iter().collect::<Vec<_>>(), vec![399]);
+
+        hv.mutate_many(std::iter::empty());
+        assert_eq!(hv.as_table(), &table[..]);
+    }
+
+    #[test]
+    fn test_mutate_final_partial_chunk() {
+        for len in [1, 2, 5, 11, 26, 67] {
+            let table: Vec<u32> = (0..len).map(|i| i % 4).collect();
+            let mut hv: HiVec<u32, 3, 3> = HiVec::new(table.clone());
+            hv.mutate(len as usize - 1, |x| *x = 50);
+            hv.mutate(len as usize, |x| *x = 60);
+            let mut expected = table;
+            *expected.last_mut().unwrap() = 50;
+            let fresh: HiVec<u32, 3, 3> = HiVec::new(expected);
+            assert_eq!(hv.clone().into_parts(), fresh.into_parts());
+            assert_eq!(hv.query_equals(50).findlast(), Some(len as usize - 1));
+        }
+    }
+
+    #[test]
+    fn test_from_fn() {
+        for n in [0, 1, 7, 100] {
+            let built: HiVec<u32, 3, 2> = HiVec::from_fn(n, |i| i as u32);
+            let fresh: HiVec<u32, 3, 2> = HiVec::new((0..n).map(|i| i as u32).collect());
+            assert_eq!(built.into_parts(), fresh.into_parts());
+        }
+        let mut calls = Vec::new();
+        let hv: HiVec<u32, 2, 4> = HiVec::from_fn(5, |i| {
+            calls.push(i);
+            10 - i as u32
+        });
+        assert_eq!(calls, vec![0, 1, 2, 3, 4]);
+        assert_eq!(hv.as_table(), &[10, 9, 8, 7, 6]);
+    }
+
+    #[test]
+    fn test_collect_hivec() {
+        let data = vec![3u32, 1, 4, 1, 5, 9, 2, 6, 5, 3, 5];
+        let hv: HiVec<_, 3, 2> = data.clone().into_iter().collect();
+        let fresh: HiVec<u32, 3, 2> = HiVec::new(data);
+        assert_eq!(
+            hv.query_equals(5).iter().collect::<Vec<_>>(),
+            vec![4, 8, 10]
+        );
+        assert_eq!(hv.into_parts(), fresh.into_parts());
+        let empty: HiVec<bool, 2, 4> = std::iter::empty().collect();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_hivec_iter() {
+        let mut hv: HiVec<u32, 3, 2> = HiVec::new(vec![5, 3, 8]);
+        hv.push(1);
+        hv.push(9);
+        assert_eq!(hv.iter().copied().collect::<Vec<_>>(), vec![5, 3, 8, 1, 9]);
+        assert_eq!(hv.iter().len(), hv.len());
+        let mut seen = Vec::new();
+        for x in &hv {
+            seen.push(*x);
+        }
+        assert_eq!(seen, hv.as_table());
+    }
+
+    #[test]
+    fn test_empty_hivec() {
+        let empty: HiVec<u32, 3, 4> = HiVec::new(vec![]);
+        assert!(empty.is_empty());
+        assert_eq!(empty.len(), 0);
+        assert_eq!(empty.query_equals(1).findnext(0), None);
+        assert_eq!(empty.query_equals(1).findlast(), None);
+        assert_eq!(empty.query_range(LatticeRange::new(9, 0)).count(), 0);
+        let (table, layers) = empty.into_parts();
+        assert!(table.is_empty());
+        assert_eq!(layers.len(), 3);
+        assert!(layers.iter().all(|l| l.is_empty()));
+
+        let one: HiVec<FreeL32, 2, 2> = HiVec::new(vec![FreeL32::new(3)]);
+        assert!(!one.is_empty());
+        assert_eq!(
+            one.query_equals(FreeL32::new(3)).iter().collect::<Vec<_>>(),
+            vec![0]
+        );
+    }
+
+    #[test]
+    fn test_xor_query() {
+        let a: Vec<u32> = (0..400).map(|i| if i < 100 { 1 } else { i % 7 }).collect();
+        let b: Vec<u32> = (0..400)
+            .map(|i| if (64..200).contains(&i) { 1 } else { i % 5 })
+            .collect();
+        let (ha, hb): (HiVec<u32, 3, 4>, HiVec<u32, 3, 4>) =
+            (HiVec::new(a.clone()), HiVec::new(b.clone()));
+        let q = ha.query_equals(1).rc().xor(hb.query_equals(1).rc());
+        let naive: Vec<usize> = (0..400).filter(|&i| (a[i] == 1) ^ (b[i] == 1)).collect();
+        assert_eq!(q.iter().collect::<Vec<_>>(), naive);
+        assert_eq!(
+            q.iter().rev().collect::<Vec<_>>(),
+            naive.iter().rev().copied().collect::<Vec<_>>()
+        );
+        assert_eq!(q.count(), naive.len());
+        // Both sides match all of 68..72, so that block holds no matches at all, but
+        // each side has matches there and hiquery can't rule it out.
+        assert!(q.hiquery(1, 17) && !q.hiquery_all(1, 17));
+        assert_eq!(
+            q.explain(70),
+            "XOR = false: left(EqualsQuery = true), right(EqualsQuery = true)"
+        );
+    }
+
+    #[test]
+    fn test_not_query() {
+        let v: Vec<u32> = (0..300).map(|i| if i < 128 { 4 } else { i % 6 }).collect();
+        let hv: HiVec<u32, 3, 4> = HiVec::new(v.clone());
+        let eq = hv.query_equals(4).rc();
+        let matches: Vec<usize> = eq.iter().collect();
+        let others: Vec<usize> = (0..300).filter(|&i| v[i] != 4).collect();
+
+        assert_eq!(eq.not().iter().collect::<Vec<_>>(), others);
+        assert_eq!(eq.not().rc().not().iter().collect::<Vec<_>>(), matches);
+
+        let not = NotQuery::new(eq.clone());
+        assert_eq!(not.iter().collect::<Vec<_>>(), others);
+        let twice = NotQuery::new(NotQuery::new(eq.clone()).rc());
+        assert_eq!(twice.iter().collect::<Vec<_>>(), matches);
+        // The full block 0..64 lets the negation skip it in one probe.
+        let probed = Probed::new(&not);
+        assert_eq!(probed.findnext(0), Some(others[0]));
+        assert!(probed.take() < 20);
+
+        // Negating a NotQuery unwraps it: NOT(x == 4) negates back to x == 4.
+        let back = not.rc().not();
+        assert_eq!(back.iter().collect::<Vec<_>>(), matches);
+        assert_eq!(format!("{:?}", back), format!("{:?}", hv.query_equals(4)));
+        for l in 1..=3 {
+            for b in 0..300usize.div_ceil(4usize.pow(l as u32)) {
+                let block = b * 4usize.pow(l as u32)..((b + 1) * 4usize.pow(l as u32)).min(300);
+                if block.clone().any(|i| v[i] != 4) {
+                    assert!(HiQuery::<3, 4>::block_has_nonmatch(&*eq, l, b));
+                }
+            }
+        }
+
+        // Queries without a NegatableQuery impl can still be wrapped, but keep the
+        // default block_has_nonmatch, so their negation can't skip any block.
+        let changes = hv.query_transitions().rc();
+        let stable: Vec<usize> = (0..300).filter(|&i| i == 0 || v[i] == v[i - 1]).collect();
+        let stable_q = NotQuery::new(changes);
+        assert_eq!(stable_q.iter().collect::<Vec<_>>(), stable);
+        assert!((0..5).all(|b| stable_q.hiquery(3, b)));
+    }
+
+    #[test]
+    fn test_any_all() {
+        let ones: HiVec<u32, 3, 4> = HiVec::new(vec![1; 1000]);
+        assert!(ones.query_equals(1).any() && ones.query_equals(1).all());
+        assert!(!ones.query_equals(2).any() && !ones.query_equals(2).all());
+
+        let mut v = vec![0u32; 1000];
+        v[777] = 5;
+        let hv: HiVec<u32, 3, 4> = HiVec::new(v);
+        let five = hv.query_equals(5);
+        let zero = hv.query_equals(0);
+        assert!(five.any() && !five.all());
+        assert!(zero.any() && !zero.all());
+        assert!(hv.query_range(LatticeRange::new(5, 0)).all());
+
+        // Both exit far sooner than a scan of all 1000 elements would.
+        let probed = Probed::new(&five);
+        assert!(probed.any());
+        assert!(probed.take() < 100);
+        let probed = Probed::new(&zero);
+        assert!(!probed.all());
+        assert!(probed.take() < 40);
+
+        let empty: HiVec<u32, 3, 4> = HiVec::new(vec![]);
+        assert!(!empty.query_equals(0).any() && empty.query_equals(0).all());
+    }
+
+    #[test]
+    fn test_first_last() {
+        let v = vec![true, false, false, true, true, false, false, false, true];
+        let hv: HiVec<_, 3, 2> = HiVec::new(v);
+        let q1 = hv.query_equals(true);
+        let q2 = hv.query_equals(false);
+        assert_eq!((q1.first(), q1.last()), (Some(0), Some(8)));
+        assert_eq!((q2.first(), q2.last()), (Some(1), Some(7)));
+
+        let none: HiVec<_, 3, 2> = HiVec::new(vec![false; 9]);
+        assert_eq!(none.query_equals(true).first(), None);
+        assert_eq!(none.query_equals(true).last(), None);
+
+        let mut v = vec![false; 1000];
+        v[3] = true;
+        let hv: HiVec<_, 3, 4> = HiVec::new(v);
+        let q = hv.query_equals(true);
+        let probed = Probed::new(&q);
+        assert_eq!(probed.last(), Some(3));
+        assert!(probed.take() < 100);
+    }
+
+    #[test]
+    fn test_iter_range() {
+        let v: Vec<u32> = (0..500).map(|i| (i / 3) % 7).collect();
+        let hv: HiVec<u32, 3, 4> = HiVec::new(v);
+        let q = hv.query_equals(2);
+        for (a, b) in [
+            (0, 500),
+            (0, 0),
+            (10, 11),
+            (17, 250),
+            (64, 128),
+            (499, 500),
+            (300, 900),
+            (600, 700),
+        ] {
+            let expected: Vec<usize> = q.iter().filter(|&i| a <= i && i < b).collect();
+            assert_eq!(q.iter_range(a..b).collect::<Vec<_>>(), expected);
+            assert_eq!(
+                q.iter_range(a..b).rev().collect::<Vec<_>>(),
+                expected.iter().rev().copied().collect::<Vec<_>>()
+            );
+        }
+        // A window near the start never looks at the rest of the vector.
+        let probed = Probed::new(&q);
+        assert_eq!(probed.iter_range(0..30).count(), 6);
+        assert!(probed.take() < 60);
+
+        // Sparse data: the nearest match outside each window is thousands of indices
+        // away, so an unbounded search would cross over a hundred top blocks.
+        let mut sparse = vec![0u32; 8192];
+        for i in [5, 20, 5000] {
+            sparse[i] = 1;
+        }
+        let hv: HiVec<u32, 3, 4> = HiVec::new(sparse);
+        let q = hv.query_equals(1);
+        let probed = Probed::new(&q);
+        assert_eq!(probed.iter_range(0..30).collect::<Vec<_>>(), vec![5, 20]);
+        assert!(probed.take() < 60);
+        assert_eq!(probed.iter_range(6000..6100).rev().count(), 0);
+        assert!(probed.take() < 60);
+        assert_eq!(
+            probed.iter_range(4000..6000).rev().collect::<Vec<_>>(),
+            vec![5000]
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_lattices() {
+        fn round_trip<T: serde::Serialize + serde::de::DeserializeOwned>(x: &T) -> (String, T) {
+            let json = serde_json::to_string(x).unwrap();
+            let back = serde_json::from_str(&json).unwrap();
+            (json, back)
+        }
+        let l = FreeL32::new(0b1011);
+        assert_eq!(round_trip(&l), ("11".to_string(), l));
+        let l = crate::lattices::FreeL64::new(1 << 63 | 5);
+        assert_eq!(round_trip(&l).1, l);
+
+        let a = AlphaNumSet::new("ab!Z9");
+        let (json, back) = round_trip(&a);
+        assert_eq!(json, "\"9Zab:\"");
+        assert_eq!(back, a);
+        // Both fallback buckets survive, and ':' itself reads back as punctuation.
+        let a = AlphaNumSet::new(":x\u{e9}");
+        assert_eq!(round_trip(&a).1, a);
+        assert_eq!(round_trip(&AlphaNumSet::new(":")).1, AlphaNumSet::new("-"));
+
+        let r = LatticeRange::new(FreeL32::new(7), FreeL32::new(1));
+        let (json, back) = round_trip(&r);
+        assert_eq!(json, r#"{"top":7,"bottom":1}"#);
+        assert_eq!(back, r);
+    }
+
+    #[test]
+    fn test_alphanum_membership() {
+        let set = AlphaNumSet::new("z0Q9aA");
+        assert!(set.contains('0') && set.contains('9') && !set.contains('5'));
+        assert!(set.contains('A') && set.contains('Q') && !set.contains('q'));
+        assert!(set.contains('a') && set.contains('z') && !set.contains('Z'));
+        assert_eq!(set.len(), 6);
+        assert_eq!(set.iter().collect::<String>(), "09AQaz");
+
+        // Punctuation shares bit 62 and non-ASCII shares bit 63.
+        let other = AlphaNumSet::new("-\u{e9}");
+        assert!(other.contains('!') && other.contains('\u{3bb}'));
+        assert!(!other.contains('a'));
+        assert_eq!(other.len(), 2);
+        assert_eq!(other.iter().collect::<Vec<_>>(), vec![':', '?']);
+        assert!(!AlphaNumSet::new("-").contains('\u{e9}'));
+
+        assert!(AlphaNumSet::new("").is_empty());
+        assert_eq!(AlphaNumSet::new("").complement().len(), 64);
+    }
+
+    #[test]
+    fn test_alphanum_display_errors() {
+        // Accepts a fixed number of characters, then fails.
+        struct Limited(usize, String);
+        impl std::fmt::Write for Limited {
+            fn write_str(&mut self, s: &str) -> std::fmt::Result {
+                if self.1.len() + s.len() > self.0 {
+                    return Err(std::fmt::Error);
+                }
+                self.1.push_str(s);
+                Ok(())
+            }
+        }
+        use std::fmt::Write;
+        let set = AlphaNumSet::new("hello worldz");
+        let mut out = Limited(3, String::new());
+        assert!(write!(out, "{set}").is_err());
+        assert_eq!(out.1, "deh");
+        let mut out = Limited(100, String::new());
+        assert!(write!(out, "{set}").is_ok());
+        assert_eq!(out.1, set.to_string());
+        assert_eq!(set.to_string(), "dehlorwz:");
+    }
+
+    #[test]
+    fn test_rank() {
+        assert_eq!(FreeL32::BOT.rank(), 0);
+        assert_eq!(FreeL32::TOP.rank(), 32);
+        assert_eq!(crate::lattices::FreeL64::BOT.rank(), 0);
+        assert_eq!(crate::lattices::FreeL64::TOP.rank(), 64);
+        assert_eq!(FreeL128::TOP.rank(), 128);
+        assert_eq!(FreeBitset::<3>::TOP.rank(), 192);
+        assert_eq!(AlphaNumSet::new("abc").rank(), 3);
+        let (a, b) = (FreeL32::new(0b0110), FreeL32::new(0b1100));
+        assert_eq!(a.join(b).rank() + a.meet(b).rank(), a.rank() + b.rank());
+    }
+
+    #[test]
+    fn test_product_lattice_arities() {
+        use crate::lattices::ProductLattice as P;
+        use std::cmp::Ordering;
+        let f = FreeL32::new;
+        let cmp3 = |a: &(FreeL32, u32, bool), b: &(FreeL32, u32, bool)| -> Option<Ordering> {
+            let parts = [
+                a.0.partial_cmp(&b.0),
+                a.1.partial_cmp(&b.1),
+                a.2.partial_cmp(&b.2),
+            ];
+            if parts.contains(&None) {
+                None
+            } else if parts.iter().all(|o| *o == Some(Ordering::Equal)) {
+                Some(Ordering::Equal)
+            } else if parts.iter().all(|o| *o != Some(Ordering::Greater)) {
+                Some(Ordering::Less)
+            } else if parts.iter().all(|o| *o != Some(Ordering::Less)) {
+                Some(Ordering::Greater)
+            } else {
+                None
+            }
+        };
+        let values: Vec<(FreeL32, u32, bool)> =
+            (0..64).map(|i| (f(i % 4), i / 16, i % 3 == 0)).collect();
+        for a in &values {
+            for b in &values {
+                assert_eq!(P(*a).partial_cmp(&P(*b)), cmp3(a, b));
+                let joined = P(*a).join(P(*b)).0;
+                assert_eq!(joined, (a.0.join(b.0), a.1.max(b.1), a.2 | b.2));
+            }
+        }
+        let top: P<(FreeL32, AlphaNumSet, (), FreeL128)> = BoundedLattice::TOP;
+        assert_eq!(top.0, (FreeL32::TOP, AlphaNumSet::TOP, (), FreeL128::TOP));
+        let bot = P::<(FreeL32, AlphaNumSet, (), FreeL128)>::BOT;
+        assert!(bot < top);
+
+        // A conjunctive query over two dimensions at once.
+        let table: Vec<P<(FreeL32, u32)>> = (0..200).map(|i| P((f(i % 8), i))).collect();
+        let hv: HiVec<_, 3, 4> = HiVec::new(table);
+        let q = hv.query_range(LatticeRange::new(P((f(0b011), 120)), P((f(0b001), 50))));
+        let naive: Vec<usize> = (50..=120).filter(|&i| [1, 3].contains(&(i % 8))).collect();
+        assert_eq!(q.iter().collect::<Vec<_>>(), naive);
+    }
+
+    #[test]
+    fn test_dual_lattice() {
+        let (a, b) = (FreeL32::new(0b0110), FreeL32::new(0b1100));
+        assert_eq!(Dual(a).join(Dual(b)), Dual(a.meet(b)));
+        assert_eq!(Dual(a).meet(Dual(b)), Dual(a.join(b)));
+        assert!(Dual(a.join(b)) < Dual(a));
+        assert_eq!(Dual(a).partial_cmp(&Dual(b)), None);
+        assert_eq!(Dual::<FreeL32>::TOP, Dual(FreeL32::BOT));
+        assert_eq!(Dual::<FreeL32>::BOT, Dual(FreeL32::TOP));
+        assert!(Dual(3) > Dual(5));
+
+        // Range and threshold queries over the dual mirror the originals.
+        let v: Vec<u32> = (0..300).map(|i| (i * 17) % 101).collect();
+        let hv: HiVec<u32, 3, 4> = HiVec::new(v.clone());
+        let dual: HiVec<Dual<u32>, 3, 4> = v.iter().map(|&x| Dual(x)).collect();
+        assert_eq!(
+            dual.query_le(Dual(60)).iter().collect::<Vec<_>>(),
+            hv.query_ge(60).iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            dual.query_range(LatticeRange::new(Dual(10), Dual(40)))
+                .iter()
+                .collect::<Vec<_>>(),
+            hv.query_range(LatticeRange::new(40, 10))
+                .iter()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_flat_lattice() {
+        use std::cmp::Ordering;
+        let v = |s: &str| Flat::Value(s.to_string());
+        assert_eq!(v("a").join(v("a")), v("a"));
+        assert_eq!(v("a").meet(v("a")), v("a"));
+        assert_eq!(v("a").join(v("b")), Flat::Top);
+        assert_eq!(v("a").meet(v("b")), Flat::Bot);
+        for x in [v("a"), Flat::Bot, Flat::Top] {
+            assert_eq!(x.clone().join(Flat::Bot), x);
+            assert_eq!(x.clone().meet(Flat::Top), x);
+            assert_eq!(x.clone().join(Flat::Top), Flat::Top);
+            assert_eq!(x.clone().meet(Flat::Bot), Flat::Bot);
+        }
+        assert_eq!(v("a").partial_cmp(&v("b")), None);
+        assert_eq!(v("a").partial_cmp(&v("a")), Some(Ordering::Equal));
+        assert!(Flat::Bot < v("a") && v("a") < Flat::Top && Flat::<String>::Bot < Flat::Top);
+
+        let colors = ["red", "green", "blue"];
+        let mut table: Vec<Flat<String>> = vec![v("red"); 64];
+        table.extend((0..100).map(|i| v(colors[i % 3])));
+        let hv: HiVec<Flat<String>, 3, 4> = HiVec::new(table.clone());
+        for c in colors {
+            let naive: Vec<usize> = (0..table.len()).filter(|&i| table[i] == v(c)).collect();
+            assert_eq!(hv.query_equals(v(c)).iter().collect::<Vec<_>>(), naive);
+        }
+        // The uniform first block is skipped without looking at its elements.
+        assert!(!hv.query_equals(v("blue")).hiquery(3, 0));
+    }
+
+    #[test]
+    fn test_set_lattice() {
+        use std::cmp::Ordering;
+        let set = |xs: &[&str]| xs.iter().map(|x| x.to_string()).collect::<SetLattice<_>>();
+        let ab = set(&["a", "b"]);
+        let bc = set(&["b", "c"]);
+        assert_eq!(set(&["a"]).partial_cmp(&ab), Some(Ordering::Less));
+        assert_eq!(ab.partial_cmp(&set(&["b"])), Some(Ordering::Greater));
+        assert_eq!(ab.partial_cmp(&set(&["b", "a"])), Some(Ordering::Equal));
+        assert_eq!(ab.partial_cmp(&bc), None);
+        assert!(SetLattice::default() <= ab);
+        assert_eq!(ab.clone().join(bc.clone()), set(&["a", "b", "c"]));
+        assert_eq!(bc.clone().join(ab.clone()), set(&["a", "b", "c"]));
+        assert_eq!(ab.clone().meet(bc.clone()), set(&["b"]));
+        assert_eq!(ab.clone().meet(set(&["c"])), SetLattice::default());
+
+        let table: Vec<SetLattice<u32>> = (0..200u32)
+            .map(|i| [i % 7, 100 + i % 5].into_iter().collect())
+            .collect();
+        let hv: HiVec<SetLattice<u32>, 3, 4> = HiVec::new(table.clone());
+        let target: SetLattice<u32> = [3, 101].into_iter().collect();
+        let naive: Vec<usize> = (0..table.len()).filter(|&i| table[i] == target).collect();
+        assert!(!naive.is_empty());
+        assert_eq!(hv.query_equals(target).iter().collect::<Vec<_>>(), naive);
+    }
+
+    #[test]
+    fn test_alphanumset_from_str_and_iter() {
+        let parsed = "abcXYZ".parse::<AlphaNumSet>().unwrap();
+        assert_eq!(parsed, AlphaNumSet::new("abcXYZ"));
+        assert_eq!(parsed.to_string(), "XYZabc");
+        let collected: AlphaNumSet = "abcXYZ".chars().collect();
+        assert_eq!(collected, parsed);
+        let mixed: AlphaNumSet = ['1', '-', 'é', 'q'].into_iter().collect();
+        assert_eq!(mixed, AlphaNumSet::new("1-éq"));
+        assert_eq!("".parse::<AlphaNumSet>().unwrap(), AlphaNumSet::BOT);
+        assert_eq!(
+            std::iter::empty().collect::<AlphaNumSet>(),
+            AlphaNumSet::BOT
+        );
+    }
+
+    #[test]
+    fn test_alphanumset_difference() {
+        let a = AlphaNumSet::new("abcdefgh0123");
+        let b = AlphaNumSet::new("aeiou0Z");
+        let diff = a.difference(b);
+        let sym = a.symmetric_difference(b);
+        for ch in "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz".chars() {
+            assert_eq!(
+                diff.contains(ch),
+                a.contains(ch) && !b.contains(ch),
+                "{}",
+                ch
+            );
+            assert_eq!(sym.contains(ch), a.contains(ch) != b.contains(ch), "{}", ch);
+        }
+        assert_eq!(diff.to_string(), "123bcdfgh");
+        assert_eq!(sym, b.symmetric_difference(a));
+        assert!(a.difference(a).is_empty());
+        assert_eq!(a.symmetric_difference(AlphaNumSet::BOT), a);
+    }
+
+    #[test]
+    fn test_constant_blocks() {
+        let mut table: Vec<u32> = (0..48).collect();
+        table.extend([7; 16]);
+        table.extend(0..40);
+        let hv: HiVec<u32, 3, 4> = HiVec::new(table.clone());
+        let naive: Vec<usize> = (0..table.len().div_ceil(16))
+            .filter(|&b| {
+                let block = &table[b * 16..((b + 1) * 16).min(table.len())];
+                block.iter().all(|&x| x == block[0])
+            })
+            .collect();
+        assert_eq!(naive, vec![3]);
+        assert_eq!(hv.constant_blocks(1).collect::<Vec<_>>(), naive);
+        assert_eq!(
+            hv.constant_blocks(0).collect::<Vec<_>>(),
+            vec![12, 13, 14, 15]
+        );
+        assert_eq!(hv.constant_blocks(2).count(), 0);
+    }
+
+    #[test]
+    fn test_layer_accessor() {
+        let table: Vec<u32> = (0..150u32).map(|i| (i * 37) % 101).collect();
+        let hv: HiVec<u32, 3, 4> = HiVec::new(table.clone());
+        assert_eq!(hv.num_layers(), 3);
+        let layer0 = hv.layer(0).unwrap();
+        assert_eq!(layer0.len(), table.len().div_ceil(4));
+        for (r, chunk) in layer0.iter().zip(table.chunks(4)) {
+            let top = chunk.iter().copied().reduce(|a, b| a.join(b)).unwrap();
+            let bottom = chunk.iter().copied().reduce(|a, b| a.meet(b)).unwrap();
+            assert_eq!((*r.top(), *r.bottom()), (top, bottom));
+        }
+        assert_eq!(hv.layer(2).unwrap().len(), table.len().div_ceil(64));
+        assert!(hv.layer(3).is_none());
+    }
+
+    #[test]
+    fn test_validate_invariant() {
+        let table: Vec<u32> = (0..150u32).map(|i| (i * 37) % 101).collect();
+        let mut hv: HiVec<u32, 3, 4> = HiVec::new(table);
+        assert!(hv.validate_invariant());
+        hv.mutate_many([(3, 500), (90, 0)]);
+        hv.push(7);
+        hv.pop();
+        assert!(hv.validate_invariant());
+
+        let saved = hv.layer(1).unwrap()[2];
+        hv.set_layer_unrepaired(1, 2, LatticeRange::new(1000, 0));
+        assert!(!hv.validate_invariant());
+        hv.set_layer_unrepaired(1, 2, saved);
+        assert!(hv.validate_invariant());
+        hv.set_unrepaired(5, 1000);
+        assert!(!hv.validate_invariant());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_new_parallel() {
+        let table: Vec<FreeL32> = (0..100_000u32)
+            .map(|i| FreeL32::new(i.wrapping_mul(2654435761) >> (i % 29)))
+            .collect();
+        let par: HiVec<FreeL32, 4, 8> = HiVec::new_parallel(table.clone());
+        let seq: HiVec<FreeL32, 4, 8> = HiVec::new(table);
+        assert!(par.validate_invariant());
+        assert!(par.into_parts() == seq.into_parts());
+        let empty: HiVec<FreeL32, 4, 8> = HiVec::new_parallel(Vec::new());
+        assert!(empty.into_parts() == HiVec::<FreeL32, 4, 8>::new(Vec::new()).into_parts());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_par_iter() {
+        use rayon::iter::ParallelIterator;
+        let table: Vec<u32> = (0..50_000u32)
+            .map(|i| i.wrapping_mul(2654435761) % 1000)
+            .collect();
+        let hv: HiVec<u32, 3, 8> = HiVec::new(table);
+        let q = hv
+            .query_range(LatticeRange::new(20, 10))
+            .rc()
+            .or(hv.query_equals(999).rc());
+        let par: Vec<usize> = q.par_iter().collect();
+        assert!(!par.is_empty());
+        assert_eq!(par, q.iter().collect::<Vec<_>>());
+        assert_eq!(hv.query_equals(5000).par_iter().count(), 0);
+        let empty: HiVec<u32, 3, 8> = HiVec::new(Vec::new());
+        assert_eq!(empty.query_equals(1).par_iter().count(), 0);
+
+        // Probed, but with a counter that can be shared between threads.
+        struct SyncProbed<'a, Q> {
+            q: &'a Q,
+            probes: std::sync::atomic::AtomicUsize,
+        }
+        impl<'a, Q: HiQuery<N, F>, const N: usize, const F: usize> HiQuery<N, F> for SyncProbed<'a, Q> {
+            fn length(&self) -> usize {
+                self.q.length()
+            }
+            fn query_at(&self, i: usize) -> bool {
+                self.probes
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                self.q.query_at(i)
+            }
+            fn hiquery(&self, layer: usize, i: usize) -> bool {
+                self.probes
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                self.q.hiquery(layer, i)
+            }
+        }
+        // Sparse data: each shard has to stop at its own end rather than run on to
+        // the next match, or the total work grows with the square of the shard count.
+        let mut sparse = vec![0u32; 65536];
+        sparse[100] = 1;
+        sparse[60000] = 1;
+        let hv: HiVec<u32, 3, 4> = HiVec::new(sparse);
+        let inner = hv.query_equals(1);
+        let q = SyncProbed {
+            q: &inner,
+            probes: Default::default(),
+        };
+        assert_eq!(q.iter().collect::<Vec<_>>(), vec![100, 60000]);
+        let sequential = q.probes.swap(0, std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(q.par_iter().collect::<Vec<_>>(), vec![100, 60000]);
+        let parallel = q.probes.into_inner();
+        assert!(parallel <= 4 * sequential, "{} vs {}", parallel, sequential);
+    }
+
+    #[test]
+    fn test_collect_indices() {
+        let table: Vec<u32> = (0..300u32).map(|i| (i * 37) % 101).collect();
+        let hv: HiVec<u32, 3, 4> = HiVec::new(table);
+        for q in [
+            hv.query_range(LatticeRange::new(30, 10)),
+            hv.query_range(LatticeRange::new(100, 0)),
+            hv.query_range(LatticeRange::new(500, 200)),
+        ] {
+            let v = q.collect_indices();
+            assert_eq!(v, q.iter().collect::<Vec<usize>>());
+            assert_eq!(v.capacity(), v.len());
+        }
+        let dynq: &dyn HiQuery<3, 4> = &hv.query_equals(5);
+        assert_eq!(
+            dynq.collect_indices(),
+            hv.query_equals(5).iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_select() {
+        let table: Vec<bool> = (0..200)
+            .map(|i| i % 7 == 0 || (40..90).contains(&i))
+            .collect();
+        let hv: HiVec<bool, 3, 4> = HiVec::new(table.clone());
+        let trues = table.iter().filter(|&&b| b).count();
+        assert_eq!(hv.select(&hv.query_equals(true)), vec![true; trues]);
+        assert_eq!(hv.select(&hv.query_equals(false)), vec![false; 200 - trues]);
+
+        let nums: HiVec<u32, 3, 4> = HiVec::new((0..200u32).map(|i| (i * 37) % 101).collect());
+        let q = nums
+            .query_range(LatticeRange::new(20, 10))
+            .rc()
+            .or(nums.query_equals(99).rc());
+        let expected: Vec<u32> = nums
+            .iter()
+            .copied()
+            .filter(|x| (10..=20).contains(x) || *x == 99)
+            .collect();
+        assert_eq!(nums.select(&q), expected);
+    }
+
+    #[test]
+    fn test_findnext_unaligned() {
+        let mut v = vec![false; 1024];
+        v[3] = true;
+        v[1000] = true;
+        let hv: HiVec<bool, 3, 4> = HiVec::new(v.clone());
+        let inner = hv.query_equals(true);
+        let q = Probed::new(&inner);
+        let mut worst = 0;
+        for i in 0..=v.len() {
+            let naive = (i..v.len()).find(|&j| v[j]);
+            assert_eq!(q.findnext(i), naive, "{}", i);
+            worst = worst.max(q.take());
+        }
+        // Up to FANOUT - 1 steps of two probes per layer climbing out of the leaves and
+        // again descending to the match, plus N + 1 probes per top block crossed.
+        assert!(worst <= 4 * 3 * 3 + 4 * 1024 / 64, "{}", worst);
+
+        let table: Vec<u32> = (0..700u32)
+            .map(|i| if i % 97 < 5 { i % 3 } else { 9 })
+            .collect();
+        let hv: HiVec<u32, 3, 4> = HiVec::new(table.clone());
+        let q = hv.query_equals(1);
+        for i in 0..table.len() {
+            assert_eq!(
+                q.findnext(i),
+                (i..table.len()).find(|&j| table[j] == 1),
+                "{}",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn test_const_query() {
+        let table: Vec<u32> = (0..300u32).map(|i| (i * 37) % 101).collect();
+        let hv: HiVec<u32, 3, 4> = HiVec::new(table.clone());
+        let len = table.len();
+        let yes = ConstQuery::<3, 4>::new(true, len);
+        let no = ConstQuery::<3, 4>::new(false, len);
+        assert_eq!(yes.count(), len);
+        assert!(yes.all());
+        assert_eq!(no.first(), None);
+        assert_eq!(no.rc().not(), yes);
+
+        // A binary fold needs a single accumulator type, so fold into an intersection.
+        let targets = [5u32, 17, 40];
+        let seed = || {
+            let mut acc = IntersectionQuery::new(len);
+            acc.push(yes);
+            acc
+        };
+        let folded = targets.iter().fold(seed(), |mut acc, &t| {
+            acc.push(hv.query_range(LatticeRange::new(100, t)));
+            acc
+        });
+        let naive: Vec<usize> = (0..len).filter(|&i| table[i] >= 40).collect();
+        assert_eq!(folded.iter().collect::<Vec<_>>(), naive);
+        assert_eq!(seed().count(), len);
+
+        let eq = hv.query_equals(17);
+        let expected: Vec<usize> = eq.iter().collect();
+        let and = yes.rc().and(hv.query_equals(17).rc());
+        let or = no.rc().or(hv.query_equals(17).rc());
+        assert_eq!(and.iter().collect::<Vec<_>>(), expected);
+        assert_eq!(or.iter().collect::<Vec<_>>(), expected);
+        let not_and = and.rc().not();
+        assert_eq!(not_and.count(), len - expected.len());
+    }
+
+    #[test]
+    fn test_boxed_query() {
+        let table: Vec<u32> = (0..300u32).map(|i| (i * 37) % 101).collect();
+        let hv: HiVec<u32, 3, 4> = HiVec::new(table.clone());
+        // A filter as it might come out of a parser: (negate, low, high) clauses, ORed.
+        let clauses = [(false, 10, 20), (false, 95, 100), (true, 3, 100)];
+        let leaves: Vec<BoxedQuery<3, 4>> = clauses
+            .iter()
+            .map(|&(negate, lo, hi)| {
+                let q = hv.query_range(LatticeRange::new(hi, lo));
+                if negate {
+                    q.rc().not().boxed()
+                } else {
+                    q.boxed()
+                }
+            })
+            .collect();
+        let q = leaves
+            .into_iter()
+            .fold(ConstQuery::new(false, table.len()).boxed(), |acc, q| {
+                acc.rc().or(q.rc()).boxed()
+            });
+        let matches = |x: u32| {
+            clauses
+                .iter()
+                .any(|&(neg, lo, hi)| (lo..=hi).contains(&x) != neg)
+        };
+        let naive: Vec<usize> = (0..table.len()).filter(|&i| matches(table[i])).collect();
+        assert_eq!(q.iter().collect::<Vec<_>>(), naive);
+        assert_eq!(q.count(), naive.len());
+        assert!(
+            q.explain(0)
+                .starts_with("OR = true: right(OutsideRangeQuery"),
+            "{}",
+            q.explain(0)
+        );
+    }
+
+    #[test]
+    fn test_owned_queries() {
+        let table: Vec<u32> = (0..300u32).map(|i| (i * 37) % 101).collect();
+        let hv: Arc<HiVec<u32, 3, 4>> = Arc::new(HiVec::new(table.clone()));
+        let eq = hv.query_equals_owned(50);
+        let range = hv.query_range_owned(LatticeRange::new(20, 10));
+        let expected_eq: Vec<usize> = hv.query_equals(50).iter().collect();
+        let expected_range: Vec<usize> = hv.query_range(LatticeRange::new(20, 10)).iter().collect();
+        let handle = std::thread::spawn(move || {
+            let both = eq.rc().or(range.rc());
+            (both.count(), both.iter().collect::<Vec<_>>())
+        });
+        let (count, found) = handle.join().unwrap();
+        let mut expected: Vec<usize> = expected_eq.into_iter().chain(expected_range).collect();
+        expected.sort();
+        assert_eq!(found, expected);
+        assert_eq!(count, expected.len());
+        assert_eq!(Arc::strong_count(&hv), 1);
+        assert_eq!(
+            format!("{:?}", hv.query_equals_owned(3)),
+            "OwnedEqualsQuery { item: 3, length: 300 }"
+        );
+    }
+
+    #[test]
+    fn test_mutate_reports_change() {
+        let table: Vec<u32> = (0..150u32).map(|i| (i * 37) % 101).collect();
+        let mut hv: HiVec<u32, 3, 4> = HiVec::new(table);
+        let saved = hv.layer(0).unwrap()[1];
+        // A stale entry stays stale unless the block around index 5 is repaired.
+        hv.set_layer_unrepaired(0, 1, LatticeRange::new(1000, 0));
+        let old = *hv.get(5).unwrap();
+        assert!(!hv.mutate(5, |x| *x = old));
+        assert!(!hv.mutate(5, |_| ()));
+        assert!(!hv.mutate(500, |x| *x = 1));
+        assert_eq!(hv.layer(0).unwrap()[1], LatticeRange::new(1000, 0));
+        hv.mutate_always(5, |_| ());
+        assert_eq!(hv.layer(0).unwrap()[1], saved);
+        assert!(hv.validate_invariant());
+
+        assert!(hv.mutate(5, |x| *x += 200));
+        assert_eq!(hv.get(5), Some(&(old + 200)));
+        assert!(hv.validate_invariant());
+        assert_eq!(hv.query_equals(old + 200).first(), Some(5));
+
+        // Keyed equality only sees the key, so a payload edit is not a change to
+        // mutate and leaves the payload carried in the layers stale.
+        let rows: Vec<Keyed<u32, &str>> = (0..16).map(|i| Keyed::new(i / 4, "old")).collect();
+        let mut kv: HiVec<Keyed<u32, &str>, 2, 4> = HiVec::new(rows);
+        assert!(!kv.mutate(0, |x| x.payload = "new"));
+        assert_eq!(kv.get(0).unwrap().payload, "new");
+        assert_eq!(kv.layer(0).unwrap()[0].top().payload, "old");
+        kv.mutate_always(0, |_| ());
+        assert_eq!(kv.layer(0).unwrap()[0].top().payload, "new");
+        assert!(kv.mutate(0, |x| x.key = 9));
+        assert_eq!(kv.layer(0).unwrap()[0].top().key, 9);
+    }
+
+    #[test]
+    fn test_set() {
+        let mut table: Vec<u32> = (0..150u32).map(|i| (i * 37) % 101).collect();
+        let mut hv: HiVec<u32, 3, 4> = HiVec::new(table.clone());
+        assert_eq!(hv.set(77, 500), Some(table[77]));
+        table[77] = 500;
+        assert_eq!(hv.set(150, 1), None);
+        assert_eq!(hv.as_table(), &table[..]);
+        assert!(hv.validate_invariant());
+        assert_eq!(*hv.layer(0).unwrap()[77 / 4].top(), 500);
+        assert_eq!(*hv.layer(2).unwrap()[1].top(), 500);
+        assert_eq!(hv.query_equals(500).iter().collect::<Vec<_>>(), vec![77]);
+        assert_eq!(hv.set(77, 3), Some(500));
+        assert!(hv.query_equals(500).first().is_none());
+    }
 }
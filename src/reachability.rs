@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::hivecs::{GeqQuery, HiVec};
+use crate::lattices::FreeL32;
+
+/*
+Assigns graph nodes to bit positions and collects one ancestor set per row.
+At most 32 distinct nodes fit in a FreeL32.
+*/
+pub struct ReachabilityBuilder<Node> {
+    bits: HashMap<Node, usize>,
+    rows: Vec<FreeL32>,
+}
+
+impl<Node: Eq + Hash> Default for ReachabilityBuilder<Node> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Node: Eq + Hash> ReachabilityBuilder<Node> {
+    pub fn new() -> Self {
+        ReachabilityBuilder {
+            bits: HashMap::new(),
+            rows: Vec::new(),
+        }
+    }
+
+    /*
+    The bit position of node, assigning the next free one on first use.
+    */
+    pub fn node_bit(&mut self, node: Node) -> usize {
+        if let Some(&bit) = self.bits.get(&node) {
+            return bit;
+        }
+        let next = self.bits.len();
+        assert!(next < 32, "More than 32 nodes");
+        self.bits.insert(node, next);
+        next
+    }
+
+    /*
+    Appends a row whose ancestor set holds the given nodes.
+    */
+    pub fn push_row(&mut self, ancestors: impl IntoIterator<Item = Node>) {
+        let set = ancestors
+            .into_iter()
+            .fold(0, |set, node| set | 1 << self.node_bit(node));
+        self.rows.push(FreeL32::new(set));
+    }
+
+    pub fn build<const N: usize, const FANOUT: usize>(self) -> ReachabilitySet<Node, N, FANOUT> {
+        ReachabilitySet {
+            bits: self.bits,
+            hiv: HiVec::new(self.rows),
+        }
+    }
+}
+
+/*
+A column of ancestor sets, queried by node rather than by bit.
+*/
+pub struct ReachabilitySet<Node, const N: usize, const FANOUT: usize> {
+    bits: HashMap<Node, usize>,
+    hiv: HiVec<FreeL32, N, FANOUT>,
+}
+
+impl<Node: Eq + Hash, const N: usize, const FANOUT: usize> ReachabilitySet<Node, N, FANOUT> {
+    pub fn as_hivec(&self) -> &HiVec<FreeL32, N, FANOUT> {
+        &self.hiv
+    }
+
+    pub fn len(&self) -> usize {
+        self.hiv.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hiv.is_empty()
+    }
+
+    /*
+    Matches rows whose ancestor set contains node, skipping blocks whose union
+    lacks it. None if node was never seen by the builder.
+    */
+    pub fn query_reachable_from(&self, node: &Node) -> Option<GeqQuery<'_, FreeL32, N, FANOUT>> {
+        let bit = *self.bits.get(node)?;
+        Some(self.hiv.query_contains(FreeL32::generator(bit)))
+    }
+}
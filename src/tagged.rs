@@ -0,0 +1,181 @@
+use std::cmp::Ordering;
+use std::fmt::{Debug, Formatter};
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+
+use crate::hiqueries::HiQuery;
+use crate::hivecs::{EqualsQuery, HiVec, RangeQuery};
+use crate::lattices::{Lattice, LatticeRange};
+
+/*
+A row index that remembers which column it came from. Marker is any type used
+purely as a tag, so indices from one column can't be passed to another.
+*/
+pub struct RowId<Marker> {
+    index: usize,
+    _marker: PhantomData<fn() -> Marker>,
+}
+
+impl<Marker> RowId<Marker> {
+    fn new(index: usize) -> Self {
+        RowId {
+            index,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn index(self) -> usize {
+        self.index
+    }
+}
+
+impl<Marker> From<RowId<Marker>> for usize {
+    fn from(row: RowId<Marker>) -> usize {
+        row.index
+    }
+}
+
+// Implemented by hand since derives would require the marker itself to implement them.
+impl<Marker> Clone for RowId<Marker> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<Marker> Copy for RowId<Marker> {}
+
+impl<Marker> PartialEq for RowId<Marker> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index
+    }
+}
+
+impl<Marker> Eq for RowId<Marker> {}
+
+impl<Marker> PartialOrd for RowId<Marker> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<Marker> Ord for RowId<Marker> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.index.cmp(&other.index)
+    }
+}
+
+impl<Marker> Hash for RowId<Marker> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.index.hash(state)
+    }
+}
+
+impl<Marker> Debug for RowId<Marker> {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_tuple("RowId").field(&self.index).finish()
+    }
+}
+
+/*
+A HiVec whose queries return RowId<Marker> instead of bare indices.
+*/
+pub struct TaggedHiVec<Marker, T, const N: usize, const FANOUT: usize> {
+    hiv: HiVec<T, N, FANOUT>,
+    _marker: PhantomData<fn() -> Marker>,
+}
+
+impl<Marker, T: Copy + Lattice, const N: usize, const FANOUT: usize>
+    TaggedHiVec<Marker, T, N, FANOUT>
+{
+    pub fn new(table: Vec<T>) -> Self {
+        Self::tag(HiVec::new(table))
+    }
+
+    pub fn tag(hiv: HiVec<T, N, FANOUT>) -> Self {
+        TaggedHiVec {
+            hiv,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn untagged(&self) -> &HiVec<T, N, FANOUT> {
+        &self.hiv
+    }
+
+    pub fn len(&self) -> usize {
+        self.hiv.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hiv.is_empty()
+    }
+
+    /*
+    The RowId for index i, if it is in bounds.
+    */
+    pub fn row(&self, i: usize) -> Option<RowId<Marker>> {
+        (i < self.len()).then(|| RowId::new(i))
+    }
+
+    pub fn get(&self, row: RowId<Marker>) -> Option<&T> {
+        self.hiv.get(row.index)
+    }
+
+    pub fn query_equals(&self, item: T) -> TaggedQuery<Marker, EqualsQuery<'_, T, N, FANOUT>> {
+        TaggedQuery::new(self.hiv.query_equals(item))
+    }
+
+    pub fn query_range(
+        &self,
+        range: LatticeRange<T>,
+    ) -> TaggedQuery<Marker, RangeQuery<'_, T, N, FANOUT>> {
+        TaggedQuery::new(self.hiv.query_range(range))
+    }
+}
+
+/*
+Wraps a query over a tagged column. Combine the untagged queries with inner(),
+then tag the result again if needed.
+*/
+pub struct TaggedQuery<Marker, Q> {
+    q: Q,
+    _marker: PhantomData<fn() -> Marker>,
+}
+
+impl<Marker, Q> TaggedQuery<Marker, Q> {
+    pub fn new(q: Q) -> Self {
+        TaggedQuery {
+            q,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &Q {
+        &self.q
+    }
+
+    pub fn findnext<const N: usize, const FANOUT: usize>(
+        &self,
+        from: RowId<Marker>,
+    ) -> Option<RowId<Marker>>
+    where
+        Q: HiQuery<N, FANOUT>,
+    {
+        self.q.findnext(from.index).map(RowId::new)
+    }
+
+    pub fn iter<const N: usize, const FANOUT: usize>(
+        &self,
+    ) -> impl Iterator<Item = RowId<Marker>> + '_
+    where
+        Q: HiQuery<N, FANOUT>,
+    {
+        self.q.iter().map(RowId::new)
+    }
+}
+
+impl<Marker, Q: Debug> Debug for TaggedQuery<Marker, Q> {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_tuple("TaggedQuery").field(&self.q).finish()
+    }
+}
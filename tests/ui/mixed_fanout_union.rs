@@ -0,0 +1,10 @@
+use latticequeries::hiqueries::UnionQuery;
+use latticequeries::hivecs::HiVec;
+
+fn main() {
+    let a: HiVec<u32, 3, 2> = HiVec::new(vec![1, 2, 3]);
+    let b: HiVec<u32, 3, 4> = HiVec::new(vec![1, 2, 3]);
+    let mut union: UnionQuery<3, 2> = UnionQuery::new(3);
+    union.push(a.query_equals(1));
+    union.push(b.query_equals(2));
+}
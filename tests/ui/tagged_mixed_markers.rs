@@ -0,0 +1,11 @@
+use latticequeries::tagged::TaggedHiVec;
+
+struct Age;
+struct Flag;
+
+fn main() {
+    let ages: TaggedHiVec<Age, u32, 2, 2> = TaggedHiVec::new(vec![30, 41, 30]);
+    let flags: TaggedHiVec<Flag, bool, 2, 2> = TaggedHiVec::new(vec![true, false, true]);
+    let row = ages.query_equals(41).iter().next().unwrap();
+    let _ = flags.get(row);
+}